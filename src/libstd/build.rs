@@ -54,5 +54,8 @@ fn main() {
         }
         println!("cargo:rustc-link-lib=c");
         println!("cargo:rustc-link-lib=compiler_rt");
+    } else if target.contains("switch") {
+        // Read by `sys::switch::os`.
+        println!("cargo:rerun-if-env-changed=SWITCH_DEFAULT_CWD");
     }
 }
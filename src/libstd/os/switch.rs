@@ -1,7 +1,6 @@
+//! Switch-specific definitions
+
 #![stable(feature = "rust1", since = "1.0.0")]
 
-pub mod ffi {
-    #![stable(feature = "rust1", since = "1.0.0")]
-    #[stable(feature = "rust1", since = "1.0.0")]
-    pub use crate::sys_common::os_str_bytes::*;
-}
\ No newline at end of file
+#[stable(feature = "rust1", since = "1.0.0")]
+pub use crate::sys::ext::*;
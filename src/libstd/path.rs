@@ -184,6 +184,23 @@ pub enum Prefix<'a> {
     /// Prefix `C:` for the given disk drive.
     #[stable(feature = "rust1", since = "1.0.0")]
     Disk(#[stable(feature = "rust1", since = "1.0.0")] u8),
+
+    /// Prefix naming the nn::fs mount a path is on, e.g., `sd:` in
+    /// `sd:/atmosphere`.
+    ///
+    /// Mount prefixes consist of the mount name followed by `:`. Like a
+    /// [`Disk`] prefix, a mount prefix needs a root after it for the path to
+    /// be absolute, and it is never verbatim.
+    ///
+    /// This variant is specific to the Switch and only exists when building
+    /// for it. Everywhere else `Prefix` keeps its usual variants, so code that
+    /// matches on it for the Switch has to handle `Mount` under
+    /// `#[cfg(target_os = "switch")]`.
+    ///
+    /// [`Disk`]: #variant.Disk
+    #[cfg(target_os = "switch")]
+    #[unstable(feature = "switch_ext", issue = "none")]
+    Mount(#[unstable(feature = "switch_ext", issue = "none")] &'a OsStr),
 }
 
 impl<'a> Prefix<'a> {
@@ -202,6 +219,8 @@ impl<'a> Prefix<'a> {
             UNC(x, y) => 2 + os_str_len(x) + if os_str_len(y) > 0 { 1 + os_str_len(y) } else { 0 },
             DeviceNS(x) => 4 + os_str_len(x),
             Disk(_) => 2,
+            #[cfg(target_os = "switch")]
+            Mount(x) => os_str_len(x) + 1,
         }
     }
 
@@ -229,7 +248,12 @@ impl<'a> Prefix<'a> {
 
     #[inline]
     fn is_drive(&self) -> bool {
-        matches!(*self, Prefix::Disk(_))
+        match *self {
+            Prefix::Disk(_) => true,
+            #[cfg(target_os = "switch")]
+            Prefix::Mount(_) => true,
+            _ => false,
+        }
     }
 
     #[inline]
@@ -1865,6 +1889,10 @@ impl Path {
     /// * On Windows, a path is absolute if it has a prefix and starts with the
     /// root: `c:\windows` is absolute, while `c:temp` and `\temp` are not.
     ///
+    /// * On the Switch, a path is absolute if it has a mount prefix and starts
+    /// with the root: `sd:/atmosphere` is absolute, while `sd:atmosphere` and
+    /// `/atmosphere` are not.
+    ///
     /// # Examples
     ///
     /// ```
@@ -1880,14 +1908,6 @@ impl Path {
         if cfg!(target_os = "redox") {
             // FIXME: Allow Redox prefixes
             self.has_root() || has_redox_scheme(self.as_u8_slice())
-        } else if cfg!(target_os = "switch") {
-            if let Some(Component::Normal(component)) = self.components().next() {
-                component.to_str()
-                    .map(|component| component.contains(":"))
-                    .unwrap_or(false)
-            } else {
-                false
-            }
         } else {
             self.has_root() && (cfg!(unix) || self.prefix().is_some())
         }
@@ -3729,6 +3749,120 @@ mod tests {
         );
     }
 
+    #[test]
+    #[cfg(target_os = "switch")]
+    pub fn test_decompositions_switch() {
+        // (path, components, has_root, is_absolute, parent, file_name)
+        let cases: &[(&str, &[&str], bool, bool, Option<&str>, Option<&str>)] = &[
+            ("sd:/", &["sd:", "/"], true, true, None, None),
+            ("sd:/a/b.txt", &["sd:", "/", "a", "b.txt"], true, true, Some("sd:/a"), Some("b.txt")),
+            ("sd:/a/", &["sd:", "/", "a"], true, true, Some("sd:/"), Some("a")),
+            ("sd:", &["sd:"], false, false, None, None),
+            ("sd:a", &["sd:", "a"], false, false, Some("sd:"), Some("a")),
+            ("/a", &["/", "a"], true, false, Some("/"), Some("a")),
+            ("a", &["a"], false, false, Some(""), Some("a")),
+            ("a/sd:/b", &["a", "sd:", "b"], false, false, Some("a/sd:"), Some("b")),
+            (":/a", &[":", "a"], false, false, Some(":"), Some("a")),
+            (
+                "save:/data/../x",
+                &["save:", "/", "data", "..", "x"],
+                true,
+                true,
+                Some("save:/data/.."),
+                Some("x"),
+            ),
+        ];
+
+        for &(path, iter, has_root, is_absolute, parent, file_name) in cases {
+            let p = Path::new(path);
+
+            let comps = p.iter().map(|c| c.to_str().unwrap()).collect::<Vec<_>>();
+            assert_eq!(comps, iter, "iter of {:?}", path);
+            let mut rev = p.iter().rev().map(|c| c.to_str().unwrap()).collect::<Vec<_>>();
+            rev.reverse();
+            assert_eq!(rev, iter, "iter().rev() of {:?}", path);
+
+            assert_eq!(p.has_root(), has_root, "has_root of {:?}", path);
+            assert_eq!(p.is_absolute(), is_absolute, "is_absolute of {:?}", path);
+            assert_eq!(p.parent().map(|p| p.to_str().unwrap()), parent, "parent of {:?}", path);
+            assert_eq!(p.file_name().and_then(OsStr::to_str), file_name, "file_name of {:?}", path);
+            assert_eq!(p.display().to_string(), path);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "switch")]
+    pub fn test_mount_prefix_switch() {
+        let cases: &[(&str, Option<&str>)] = &[
+            ("sd:/", Some("sd")),
+            ("rom:/data.arc", Some("rom")),
+            ("save:", Some("save")),
+            ("sd:a", Some("sd")),
+            ("/sd:/", None),
+            ("a/b:/", None),
+            (":/a", None),
+            ("sd", None),
+            ("", None),
+        ];
+
+        for &(path, mount) in cases {
+            let prefix = match Path::new(path).components().next() {
+                Some(Component::Prefix(p)) => match p.kind() {
+                    Prefix::Mount(name) => {
+                        assert!(!p.kind().is_verbatim(), "is_verbatim of {:?}", path);
+                        assert_eq!(p.kind().len(), name.len() + 1, "len of {:?}", path);
+                        Some(name.to_str().unwrap())
+                    }
+                    kind => panic!("unexpected prefix {:?} in {:?}", kind, path),
+                },
+                _ => None,
+            };
+            assert_eq!(prefix, mount, "mount of {:?}", path);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "switch")]
+    pub fn test_push_switch() {
+        // (base, pushed, expected)
+        let cases: &[(&str, &str, &str)] = &[
+            ("sd:/a", "b", "sd:/a/b"),
+            ("sd:/a/", "b", "sd:/a/b"),
+            ("sd:/", "b", "sd:/b"),
+            ("sd:/a", "../b", "sd:/a/../b"),
+            ("sd:/a", "rom:/x", "rom:/x"),
+            ("sd:/a/b", "/c", "sd:/c"),
+            ("sd:/a", "sd:b", "sd:b"),
+            ("sd:", "b", "sd:b"),
+            ("a", "sd:/b", "sd:/b"),
+        ];
+
+        for &(base, pushed, expected) in cases {
+            let mut actual = PathBuf::from(base);
+            actual.push(pushed);
+            assert_eq!(actual.to_str(), Some(expected), "pushing {:?} onto {:?}", pushed, base);
+        }
+    }
+
+    #[test]
+    #[cfg(target_os = "switch")]
+    pub fn test_strip_prefix_switch() {
+        // (path, base, expected)
+        let cases: &[(&str, &str, Option<&str>)] = &[
+            ("sd:/a/b", "sd:/", Some("a/b")),
+            ("sd:/a/b", "sd:/a", Some("b")),
+            ("sd:/a/b", "sd:/a/b", Some("")),
+            ("sd:/a/b", "rom:/a", None),
+            ("sd:/a/b", "/a", None),
+            ("sd:/a/b", "sd:a", None),
+        ];
+
+        for &(path, base, expected) in cases {
+            let actual = Path::new(path).strip_prefix(base).ok().map(|p| p.to_str().unwrap());
+            assert_eq!(actual, expected, "stripping {:?} from {:?}", base, path);
+        }
+    }
+
     #[test]
     pub fn test_stem_ext() {
         t!("foo",
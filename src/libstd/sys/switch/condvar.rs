@@ -1,92 +1,73 @@
 use crate::cell::UnsafeCell;
-use crate::sys::mutex::{self, Mutex};
+use crate::cmp;
+use crate::mem::MaybeUninit;
+use crate::sync::atomic::AtomicUsize;
+use crate::sys::mutex::{self, is_initialized, lazy_init, Mutex};
 use crate::time::Duration;
 
+use nnsdk::os::{ConditionVariableStatus, ConditionVariableType};
+use nnsdk::TimeSpan;
+
 pub struct Condvar {
-    inner: UnsafeCell<libc::pthread_cond_t>,
+    inner: UnsafeCell<MaybeUninit<ConditionVariableType>>,
+    state: AtomicUsize,
 }
 
 unsafe impl Send for Condvar {}
 unsafe impl Sync for Condvar {}
 
-const TIMESPEC_MAX: libc::timespec =
-    libc::timespec { tv_sec: <libc::time_t>::max_value(), tv_nsec: 1_000_000_000 - 1 };
-
-fn saturating_cast_to_time_t(value: u64) -> libc::time_t {
-    if value > <libc::time_t>::max_value() as u64 {
-        <libc::time_t>::max_value()
-    } else {
-        value as libc::time_t
-    }
-}
-
 impl Condvar {
     pub const fn new() -> Condvar {
         // Might be moved and address is changing it is better to avoid
-        // initialization of potentially opaque OS data before it landed
-        Condvar { inner: UnsafeCell::new(libc::PTHREAD_COND_INITIALIZER) }
+        // initialization of potentially opaque OS data before it landed, see
+        // `mutex::lazy_init`.
+        Condvar {
+            inner: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicUsize::new(mutex::UNINITIALIZED),
+        }
     }
 
-    pub unsafe fn init(&mut self) {
-        use crate::mem::MaybeUninit;
-        let mut attr = MaybeUninit::<libc::pthread_condattr_t>::uninit();
-        let r = libc::pthread_condattr_init(attr.as_mut_ptr());
-        assert_eq!(r, 0);
-        let r = libc::pthread_cond_init(self.inner.get(), attr.as_ptr());
-        assert_eq!(r, 0);
-        let r = libc::pthread_condattr_destroy(attr.as_mut_ptr());
-        assert_eq!(r, 0);
-    }
+    #[inline]
+    pub unsafe fn init(&mut self) {}
 
     #[inline]
     pub unsafe fn notify_one(&self) {
-        let r = libc::pthread_cond_signal(self.inner.get());
-        debug_assert_eq!(r, 0);
+        nnsdk::os::SignalConditionVariable(self.get());
     }
 
     #[inline]
     pub unsafe fn notify_all(&self) {
-        let r = libc::pthread_cond_broadcast(self.inner.get());
-        debug_assert_eq!(r, 0);
+        nnsdk::os::BroadcastConditionVariable(self.get());
     }
 
     #[inline]
     pub unsafe fn wait(&self, mutex: &Mutex) {
-        let r = libc::pthread_cond_wait(self.inner.get(), mutex::raw(mutex));
-        debug_assert_eq!(r, 0);
+        nnsdk::os::WaitConditionVariable(self.get(), mutex::raw(mutex));
     }
 
-    // This implementation is used on systems that support pthread_condattr_setclock
-    // where we configure condition variable to use monotonic clock (instead of
-    // default system clock). This approach avoids all problems that result
-    // from changes made to the system time.
-    #[cfg(not(any(target_os = "macos", target_os = "ios", target_os = "android")))]
+    // nn::os takes a relative timeout, so unlike the pthread implementation
+    // there is no need to read a clock and build an absolute deadline here.
     pub unsafe fn wait_timeout(&self, mutex: &Mutex, dur: Duration) -> bool {
-        use crate::mem;
-
-        let mut now: libc::timespec = mem::zeroed();
-        let r = libc::clock_gettime(libc::CLOCK_MONOTONIC, &mut now);
-        assert_eq!(r, 0);
-
-        // Nanosecond calculations can't overflow because both values are below 1e9.
-        let nsec = dur.subsec_nanos() + now.tv_nsec as u32;
-
-        let sec = saturating_cast_to_time_t(dur.as_secs())
-            .checked_add((nsec / 1_000_000_000) as libc::time_t)
-            .and_then(|s| s.checked_add(now.tv_sec));
-        let nsec = nsec % 1_000_000_000;
-
-        let timeout =
-            sec.map(|s| libc::timespec { tv_sec: s, tv_nsec: nsec as _ }).unwrap_or(TIMESPEC_MAX);
-
-        let r = libc::pthread_cond_timedwait(self.inner.get(), mutex::raw(mutex), &timeout);
-        assert!(r == libc::ETIMEDOUT || r == 0);
-        r == 0
+        let nanos = cmp::min(dur.as_nanos(), i64::max_value() as u128);
+        let status = nnsdk::os::TimedWaitConditionVariable(
+            self.get(),
+            mutex::raw(mutex),
+            TimeSpan::nano(nanos as u64),
+        );
+        status != ConditionVariableStatus::Timeout
     }
 
     #[inline]
     pub unsafe fn destroy(&self) {
-        let r = libc::pthread_cond_destroy(self.inner.get());
-        debug_assert_eq!(r, 0);
+        if is_initialized(&self.state) {
+            nnsdk::os::FinalizeConditionVariable(self.inner.get() as *mut ConditionVariableType);
+        }
+    }
+
+    #[inline]
+    unsafe fn get(&self) -> *mut ConditionVariableType {
+        let inner = self.inner.get() as *mut ConditionVariableType;
+        lazy_init(&self.state, || nnsdk::os::InitializeConditionVariable(inner));
+        inner
     }
 }
@@ -1,12 +1,10 @@
-use crate::arch::wasm32;
-use crate::cmp;
-use crate::mem;
-use crate::sync::atomic::{AtomicUsize, Ordering::SeqCst};
+use crate::sync::atomic::{AtomicU32, Ordering::SeqCst};
+use crate::sys::futex::{futex_wait, futex_wake, futex_wake_all};
 use crate::sys::mutex::Mutex;
 use crate::time::Duration;
 
 pub struct Condvar {
-    cnt: AtomicUsize,
+    cnt: AtomicU32,
 }
 
 // Condition variables are implemented with a simple counter internally that is
@@ -32,7 +30,7 @@ pub struct Condvar {
 
 impl Condvar {
     pub const fn new() -> Condvar {
-        Condvar { cnt: AtomicUsize::new(0) }
+        Condvar { cnt: AtomicU32::new(0) }
     }
 
     #[inline]
@@ -42,13 +40,13 @@ impl Condvar {
 
     pub unsafe fn notify_one(&self) {
         self.cnt.fetch_add(1, SeqCst);
-        wasm32::atomic_notify(self.ptr(), 1);
+        futex_wake(&self.cnt, 1);
     }
 
     #[inline]
     pub unsafe fn notify_all(&self) {
         self.cnt.fetch_add(1, SeqCst);
-        wasm32::atomic_notify(self.ptr(), u32::max_value()); // -1 == "wake everyone"
+        futex_wake_all(&self.cnt);
     }
 
     pub unsafe fn wait(&self, mutex: &Mutex) {
@@ -60,35 +58,24 @@ impl Condvar {
         // thread. Incrementing the counter after we unlock the mutex will
         // prevent us from sleeping and otherwise the call to `wake` will
         // wake us up once we're asleep.
-        let ticket = self.cnt.load(SeqCst) as i32;
+        let ticket = self.cnt.load(SeqCst);
         mutex.unlock();
-        let val = wasm32::i32_atomic_wait(self.ptr(), ticket, -1);
-        // 0 == woken, 1 == not equal to `ticket`, 2 == timeout (shouldn't happen)
-        debug_assert!(val == 0 || val == 1);
+        futex_wait(&self.cnt, ticket, None);
         mutex.lock();
     }
 
     pub unsafe fn wait_timeout(&self, mutex: &Mutex, dur: Duration) -> bool {
-        let ticket = self.cnt.load(SeqCst) as i32;
+        let ticket = self.cnt.load(SeqCst);
         mutex.unlock();
-        let nanos = dur.as_nanos();
-        let nanos = cmp::min(i64::max_value() as u128, nanos);
-
-        // If the return value is 2 then a timeout happened, so we return
-        // `false` as we weren't actually notified.
-        let ret = wasm32::i32_atomic_wait(self.ptr(), ticket, nanos as i64) != 2;
+        // `futex_wait` returns `false` if a timeout happened, in which case
+        // we weren't actually notified.
+        let ret = futex_wait(&self.cnt, ticket, Some(dur));
         mutex.lock();
-        return ret;
+        ret
     }
 
     #[inline]
     pub unsafe fn destroy(&self) {
         // nothing to do
     }
-
-    #[inline]
-    fn ptr(&self) -> *mut i32 {
-        assert_eq!(mem::size_of::<usize>(), mem::size_of::<i32>());
-        self.cnt.as_mut_ptr() as *mut i32
-    }
 }
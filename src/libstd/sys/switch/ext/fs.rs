@@ -0,0 +1,237 @@
+//! Switch-specific extensions to primitives in the `std::fs` module.
+
+#![unstable(feature = "switch_ext", issue = "none")]
+
+use crate::ffi::CString;
+use crate::fs::{File, OpenOptions};
+use crate::io;
+use crate::path::Path;
+use crate::sys;
+use crate::sys_common::{AsInner, AsInnerMut};
+
+/// Switch-specific extensions to [`File`].
+///
+/// nn::fs reads and writes at explicit offsets anyway, so these don't touch
+/// the file's cursor, and any number of threads can use them on the same
+/// `&File` at once.
+///
+/// [`File`]: ../../../../std/fs/struct.File.html
+pub trait FileExt {
+    /// Reads a number of bytes starting from a given offset.
+    ///
+    /// Returns the number of bytes read, which is zero at or past the end of
+    /// the file. The current file cursor is not affected by this function.
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize>;
+
+    /// Reads the exact number of bytes required to fill `buf` from the given
+    /// offset.
+    ///
+    /// Similar to [`Read::read_exact`] but uses [`read_at`] instead of `read`.
+    /// Hitting the end of the file before `buf` is full is an error of the
+    /// kind [`ErrorKind::UnexpectedEof`].
+    ///
+    /// [`Read::read_exact`]: ../../../../std/io/trait.Read.html#method.read_exact
+    /// [`read_at`]: #tymethod.read_at
+    /// [`ErrorKind::UnexpectedEof`]: ../../../../std/io/enum.ErrorKind.html#variant.UnexpectedEof
+    fn read_exact_at(&self, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.read_at(buf, offset) {
+                Ok(0) => break,
+                Ok(n) => {
+                    let tmp = buf;
+                    buf = &mut tmp[n..];
+                    offset += n as u64;
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        if !buf.is_empty() {
+            Err(io::Error::new(io::ErrorKind::UnexpectedEof, "failed to fill whole buffer"))
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Writes a number of bytes starting from a given offset.
+    ///
+    /// Returns the number of bytes written. Writing beyond the end of the file
+    /// extends it. The current file cursor is not affected by this function,
+    /// not even for files opened in append mode.
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize>;
+
+    /// Attempts to write an entire buffer starting from a given offset.
+    ///
+    /// Similar to [`Write::write_all`] but uses [`write_at`] instead of
+    /// `write`.
+    ///
+    /// [`Write::write_all`]: ../../../../std/io/trait.Write.html#method.write_all
+    /// [`write_at`]: #tymethod.write_at
+    fn write_all_at(&self, mut buf: &[u8], mut offset: u64) -> io::Result<()> {
+        while !buf.is_empty() {
+            match self.write_at(buf, offset) {
+                Ok(0) => {
+                    return Err(io::Error::new(
+                        io::ErrorKind::WriteZero,
+                        "failed to write whole buffer",
+                    ));
+                }
+                Ok(n) => {
+                    buf = &buf[n..];
+                    offset += n as u64
+                }
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+impl FileExt for File {
+    fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.as_inner().read_at(buf, offset)
+    }
+
+    fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.as_inner().write_at(buf, offset)
+    }
+}
+
+/// Switch-specific extensions to [`OpenOptions`].
+///
+/// [`OpenOptions`]: ../../../../std/fs/struct.OpenOptions.html
+pub trait OpenOptionsExt {
+    /// Makes [`File::sync_all`] commit the save data mount the file is on.
+    ///
+    /// nn::fs won't commit a mount while any of its files are open for
+    /// writing, so the file's handle is briefly closed and reopened for the
+    /// commit. Other files on the mount still have to be closed, or
+    /// `sync_all` fails. [`File::sync_data`] and `flush` never commit.
+    ///
+    /// [`File::sync_all`]: ../../../../std/fs/struct.File.html#method.sync_all
+    /// [`File::sync_data`]: ../../../../std/fs/struct.File.html#method.sync_data
+    fn commit_on_sync(&mut self, commit: bool) -> &mut Self;
+}
+
+impl OpenOptionsExt for OpenOptions {
+    fn commit_on_sync(&mut self, commit: bool) -> &mut OpenOptions {
+        self.as_inner_mut().commit_on_sync(commit);
+        self
+    }
+}
+
+fn mount_name(name: &str) -> io::Result<CString> {
+    CString::new(name).map_err(|_| {
+        io::Error::new(io::ErrorKind::InvalidInput, "mount name contained a nul byte")
+    })
+}
+
+/// Mounts the save data of the current user under `name`, making it
+/// available as `name:/`.
+///
+/// The current user is the one that last opened their account in the running
+/// title. Nothing written to the mount is persisted until it is [`commit`]ted.
+///
+/// [`commit`]: fn.commit.html
+pub fn mount_save_data(name: &str) -> io::Result<()> {
+    sys::fs::mount_save_data(&mount_name(name)?)
+}
+
+/// Mounts the running title's device save data, which is shared by all users,
+/// under `name`.
+///
+/// Nothing written to the mount is persisted until it is [`commit`]ted.
+///
+/// [`commit`]: fn.commit.html
+pub fn mount_device_save_data(name: &str) -> io::Result<()> {
+    sys::fs::mount_device_save_data(&mount_name(name)?)
+}
+
+/// Persists everything written to the save data mounted as `name`.
+///
+/// This fails as long as any file on the mount is open for writing.
+pub fn commit(name: &str) -> io::Result<()> {
+    sys::fs::commit(&mount_name(name)?)
+}
+
+/// Mounts the SD card under `name`.
+///
+/// Fails with [`ErrorKind::AlreadyExists`] if the name is already taken, as do
+/// all the other mount functions in this module.
+///
+/// [`ErrorKind::AlreadyExists`]: ../../../../std/io/enum.ErrorKind.html#variant.AlreadyExists
+pub fn mount_sd(name: &str) -> io::Result<()> {
+    sys::fs::mount_sd(&mount_name(name)?)
+}
+
+/// Mounts the running title's RomFS under `name`.
+///
+/// The mount's cache is allocated on the heap and freed by [`unmount`].
+///
+/// [`unmount`]: fn.unmount.html
+pub fn mount_rom(name: &str) -> io::Result<()> {
+    sys::fs::mount_rom(&mount_name(name)?)
+}
+
+/// Unmounts a mount previously made through this module.
+///
+/// Uncommitted changes to save data are lost. All files and directories on
+/// the mount have to be closed first, nn::fs aborts the process otherwise.
+pub fn unmount(name: &str) -> io::Result<()> {
+    sys::fs::unmount(&mount_name(name)?)
+}
+
+/// Lists the names of the mounts made through this module.
+///
+/// Mounts set up by the title itself, like the `sd` mount paths default to,
+/// are not known to std and not part of the list.
+pub fn mounts() -> Vec<String> {
+    sys::fs::mounts()
+}
+
+/// Returns the number of bytes that can still be written to the mount `path`
+/// is on.
+///
+/// Relative paths are on the mount of the current directory.
+pub fn free_space<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    sys::fs::free_space(path.as_ref())
+}
+
+/// Returns the total size of the mount `path` is on, in bytes.
+pub fn total_space<P: AsRef<Path>>(path: P) -> io::Result<u64> {
+    sys::fs::total_space(path.as_ref())
+}
+
+/// Commits a save data mount when dropped.
+///
+/// Errors are ignored on drop, use [`commit`] to handle them.
+///
+/// [`commit`]: #method.commit
+#[derive(Debug)]
+pub struct CommitGuard {
+    name: Option<CString>,
+}
+
+impl CommitGuard {
+    /// Creates a guard that commits the save data mounted as `name`.
+    pub fn new(name: &str) -> io::Result<CommitGuard> {
+        Ok(CommitGuard { name: Some(mount_name(name)?) })
+    }
+
+    /// Commits the mount right away, instead of on drop.
+    pub fn commit(mut self) -> io::Result<()> {
+        match self.name.take() {
+            Some(name) => sys::fs::commit(&name),
+            None => Ok(()),
+        }
+    }
+}
+
+impl Drop for CommitGuard {
+    fn drop(&mut self) {
+        if let Some(name) = self.name.take() {
+            let _ = sys::fs::commit(&name);
+        }
+    }
+}
@@ -0,0 +1,20 @@
+pub mod ffi;
+pub mod fs;
+pub mod net;
+pub mod thread;
+
+/// A prelude for conveniently writing platform-specific code.
+///
+/// Includes all extension traits, and some important type definitions.
+#[stable(feature = "rust1", since = "1.0.0")]
+pub mod prelude {
+    #[doc(no_inline)]
+    #[stable(feature = "rust1", since = "1.0.0")]
+    pub use crate::sys::ext::ffi::{OsStrExt, OsStringExt};
+    #[doc(no_inline)]
+    #[unstable(feature = "switch_ext", issue = "none")]
+    pub use crate::sys::ext::fs::{FileExt, OpenOptionsExt};
+    #[doc(no_inline)]
+    #[unstable(feature = "switch_ext", issue = "none")]
+    pub use crate::sys::ext::thread::{BuilderExt, JoinHandleExt};
+}
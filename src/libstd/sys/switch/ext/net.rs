@@ -0,0 +1,462 @@
+//! Switch-specific networking functionality.
+
+#![unstable(feature = "switch_ext", issue = "none")]
+
+use crate::cmp;
+use crate::fmt;
+use crate::io;
+use crate::net::{TcpListener, TcpStream, UdpSocket};
+use crate::ops::BitOr;
+use crate::os::raw::c_int;
+use crate::sys;
+use crate::sys::cvt;
+use crate::sys_common::AsInner;
+use crate::time::Duration;
+
+/// How the socket service is set up, see [`configure`].
+///
+/// [`configure`]: fn.configure.html
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SocketConfig {
+    /// Size of the memory pool handed to nn::socket, which all socket
+    /// buffers are allocated from. Has to be a multiple of the page size.
+    pub pool_size: usize,
+    /// Send buffer size of every TCP socket std creates, zero keeps
+    /// nn::socket's default.
+    pub tcp_tx_buf: usize,
+    /// Receive buffer size of every TCP socket std creates, zero keeps
+    /// nn::socket's default.
+    pub tcp_rx_buf: usize,
+    /// How many socket calls may block at the same time.
+    pub concurrency: usize,
+}
+
+impl Default for SocketConfig {
+    /// What std uses when no configuration was given, enough for a handful of
+    /// connections.
+    fn default() -> SocketConfig {
+        SocketConfig {
+            pool_size: 0x60_0000,
+            tcp_tx_buf: 0x8000,
+            tcp_rx_buf: 0x1_0000,
+            concurrency: 14,
+        }
+    }
+}
+
+/// Initializes the socket service with `config`.
+///
+/// std initializes it with the [default configuration] the first time a socket
+/// is created or a name is looked up, so this has to be called before that.
+/// Fails with [`ErrorKind::AlreadyExists`] otherwise. If the title already
+/// initialized nn::socket itself, its configuration stays in effect, except
+/// for the TCP buffer sizes.
+///
+/// [default configuration]: struct.SocketConfig.html#impl-Default
+/// [`ErrorKind::AlreadyExists`]: ../../../../std/io/enum.ErrorKind.html#variant.AlreadyExists
+///
+/// # Examples
+///
+/// ```no_run
+/// #![feature(switch_ext)]
+/// use std::os::switch::net::{configure, SocketConfig};
+///
+/// // Room for a couple dozen connections.
+/// configure(SocketConfig { pool_size: 0x100_0000, concurrency: 32, ..Default::default() })?;
+/// # std::io::Result::Ok(())
+/// ```
+pub fn configure(config: SocketConfig) -> io::Result<()> {
+    sys::net::configure(&config)
+}
+
+/// Finalizes the socket service and frees its memory pool, if std initialized
+/// it.
+///
+/// This happens on its own when the plugin is unloaded through nn::ro, which
+/// calls the module's `__custom_fini`. Plugins that stay loaded until the
+/// title exits have to call this themselves if they want the pool back
+/// earlier. All sockets have to be closed first. Using sockets again
+/// afterwards initializes the service anew.
+pub fn shutdown() -> io::Result<()> {
+    sys::net::shutdown()
+}
+
+mod private {
+    pub trait Sealed {}
+}
+
+/// A socket that can be registered with a [`Poller`].
+///
+/// This is implemented for [`TcpStream`], [`TcpListener`] and [`UdpSocket`].
+///
+/// [`Poller`]: struct.Poller.html
+/// [`TcpStream`]: ../../../../std/net/struct.TcpStream.html
+/// [`TcpListener`]: ../../../../std/net/struct.TcpListener.html
+/// [`UdpSocket`]: ../../../../std/net/struct.UdpSocket.html
+pub trait Source: private::Sealed {
+    #[doc(hidden)]
+    fn raw_socket(&self) -> c_int;
+}
+
+macro_rules! impl_source {
+    ($($t:ty)*) => ($(
+        impl private::Sealed for $t {}
+
+        impl Source for $t {
+            fn raw_socket(&self) -> c_int {
+                *self.as_inner().socket().as_inner()
+            }
+        }
+    )*)
+}
+
+impl_source! { TcpStream TcpListener UdpSocket }
+
+/// The readiness a [`Poller`] should wait for.
+///
+/// Combine interests with `|`, e.g. `Interest::READABLE | Interest::WRITABLE`.
+///
+/// [`Poller`]: struct.Poller.html
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Interest(i16);
+
+impl Interest {
+    /// Wait for data to read, a connection to accept or the peer hanging up.
+    pub const READABLE: Interest = Interest(libc::POLLIN);
+    /// Wait for room to write, or for a connection attempt to finish.
+    pub const WRITABLE: Interest = Interest(libc::POLLOUT);
+
+    /// Whether this includes [`READABLE`](#associatedconstant.READABLE).
+    pub fn is_readable(self) -> bool {
+        self.0 & libc::POLLIN != 0
+    }
+
+    /// Whether this includes [`WRITABLE`](#associatedconstant.WRITABLE).
+    pub fn is_writable(self) -> bool {
+        self.0 & libc::POLLOUT != 0
+    }
+}
+
+impl BitOr for Interest {
+    type Output = Interest;
+
+    fn bitor(self, other: Interest) -> Interest {
+        Interest(self.0 | other.0)
+    }
+}
+
+impl fmt::Debug for Interest {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Interest")
+            .field("readable", &self.is_readable())
+            .field("writable", &self.is_writable())
+            .finish()
+    }
+}
+
+/// A readiness event reported by [`Poller::poll`].
+///
+/// [`Poller::poll`]: struct.Poller.html#method.poll
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub struct Event {
+    token: usize,
+    revents: i16,
+}
+
+impl Event {
+    /// The token the socket was registered with.
+    pub fn token(&self) -> usize {
+        self.token
+    }
+
+    /// Whether reading from the socket won't block. This includes the peer
+    /// having hung up, which reads report as the end of the stream.
+    pub fn is_readable(&self) -> bool {
+        self.revents & (libc::POLLIN | libc::POLLHUP) != 0
+    }
+
+    /// Whether writing to the socket won't block.
+    pub fn is_writable(&self) -> bool {
+        self.revents & libc::POLLOUT != 0
+    }
+
+    /// Whether the peer hung up.
+    pub fn is_hangup(&self) -> bool {
+        self.revents & libc::POLLHUP != 0
+    }
+
+    /// Whether there is an error pending on the socket, or it isn't a valid
+    /// socket anymore. Use `take_error` on the socket to find out which.
+    pub fn is_error(&self) -> bool {
+        self.revents & (libc::POLLERR | libc::POLLNVAL) != 0
+    }
+}
+
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Event")
+            .field("token", &self.token)
+            .field("readable", &self.is_readable())
+            .field("writable", &self.is_writable())
+            .field("hangup", &self.is_hangup())
+            .field("error", &self.is_error())
+            .finish()
+    }
+}
+
+/// Waits for any number of sockets to become ready, using `poll`.
+///
+/// Sockets are registered with a token that identifies them in the events
+/// [`poll`] reports. They should be put into non-blocking mode, since being
+/// ready doesn't guarantee that a single operation won't block, and have to
+/// stay open for as long as they are registered.
+///
+/// Readiness is level-triggered: a socket is reported on every call to
+/// [`poll`] for as long as it is ready.
+///
+/// # Examples
+///
+/// ```no_run
+/// #![feature(switch_ext)]
+/// use std::net::TcpListener;
+/// use std::os::switch::net::{Interest, Poller};
+/// use std::time::Duration;
+///
+/// let listener = TcpListener::bind("0.0.0.0:8080")?;
+/// listener.set_nonblocking(true)?;
+///
+/// let mut poller = Poller::new();
+/// poller.register(&listener, 0, Interest::READABLE)?;
+///
+/// let mut events = Vec::new();
+/// // Never block the hooked game thread.
+/// poller.poll(&mut events, Some(Duration::from_secs(0)))?;
+/// for event in &events {
+///     if event.token() == 0 {
+///         let (stream, addr) = listener.accept()?;
+///         println!("connection from {}", addr);
+///         # drop(stream);
+///     }
+/// }
+/// # std::io::Result::Ok(())
+/// ```
+///
+/// [`poll`]: #method.poll
+#[derive(Default)]
+pub struct Poller {
+    fds: Vec<libc::pollfd>,
+    tokens: Vec<usize>,
+}
+
+impl Poller {
+    /// Creates a poller without any sockets registered.
+    pub fn new() -> Poller {
+        Poller { fds: Vec::new(), tokens: Vec::new() }
+    }
+
+    fn position(&self, fd: c_int) -> Option<usize> {
+        self.fds.iter().position(|pollfd| pollfd.fd == fd)
+    }
+
+    /// Starts waiting for `socket` to become ready as described by
+    /// `interest`.
+    ///
+    /// Fails with [`ErrorKind::AlreadyExists`] if the socket is already
+    /// registered.
+    ///
+    /// [`ErrorKind::AlreadyExists`]: ../../../../std/io/enum.ErrorKind.html#variant.AlreadyExists
+    pub fn register<S: Source>(
+        &mut self,
+        socket: &S,
+        token: usize,
+        interest: Interest,
+    ) -> io::Result<()> {
+        let fd = socket.raw_socket();
+        if self.position(fd).is_some() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "socket is already registered",
+            ));
+        }
+        self.fds.push(libc::pollfd { fd, events: interest.0, revents: 0 });
+        self.tokens.push(token);
+        Ok(())
+    }
+
+    /// Changes the token and interest of a registered socket.
+    pub fn reregister<S: Source>(
+        &mut self,
+        socket: &S,
+        token: usize,
+        interest: Interest,
+    ) -> io::Result<()> {
+        let index = self.index(socket)?;
+        self.fds[index].events = interest.0;
+        self.tokens[index] = token;
+        Ok(())
+    }
+
+    /// Stops waiting for `socket`.
+    pub fn deregister<S: Source>(&mut self, socket: &S) -> io::Result<()> {
+        let index = self.index(socket)?;
+        self.fds.swap_remove(index);
+        self.tokens.swap_remove(index);
+        Ok(())
+    }
+
+    fn index<S: Source>(&self, socket: &S) -> io::Result<usize> {
+        self.position(socket.raw_socket())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "socket is not registered"))
+    }
+
+    /// Waits until at least one registered socket is ready, or `timeout` has
+    /// passed, and replaces the contents of `events` with the sockets that are
+    /// ready.
+    ///
+    /// A timeout of zero only checks which sockets are ready without blocking
+    /// at all, `None` waits for as long as it takes. Returns the number of
+    /// events, which is zero if the timeout expired.
+    pub fn poll(
+        &mut self,
+        events: &mut Vec<Event>,
+        timeout: Option<Duration>,
+    ) -> io::Result<usize> {
+        events.clear();
+        let timeout = match timeout {
+            // Round up, a short timeout shouldn't turn into a busy loop.
+            Some(timeout) => {
+                let millis = timeout
+                    .as_secs()
+                    .saturating_mul(1_000)
+                    .saturating_add((timeout.subsec_nanos() as u64 + 999_999) / 1_000_000);
+                cmp::min(millis, c_int::max_value() as u64) as c_int
+            }
+            None => -1,
+        };
+
+        let ready = loop {
+            let r = unsafe { libc::poll(self.fds.as_mut_ptr(), self.fds.len() as _, timeout) };
+            match cvt(r) {
+                Err(ref e) if e.kind() == io::ErrorKind::Interrupted => {}
+                result => break result?,
+            }
+        };
+
+        if ready > 0 {
+            for (pollfd, &token) in self.fds.iter().zip(&self.tokens) {
+                if pollfd.revents != 0 {
+                    events.push(Event { token, revents: pollfd.revents });
+                }
+            }
+        }
+        Ok(events.len())
+    }
+}
+
+impl fmt::Debug for Poller {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_map()
+            .entries(self.fds.iter().zip(&self.tokens).map(|(pollfd, token)| {
+                (token, Interest(pollfd.events))
+            }))
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{configure, Interest, Poller, SocketConfig};
+    use crate::io::{ErrorKind, Read, Write};
+    use crate::net::{TcpListener, TcpStream, UdpSocket};
+    use crate::time::Duration;
+
+    const TIMEOUT: Option<Duration> = Some(Duration::from_secs(5));
+
+    #[test]
+    fn nonblocking_accept_and_read() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        assert_eq!(listener.accept().unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let mut poller = Poller::new();
+        poller.register(&listener, 0, Interest::READABLE).unwrap();
+        let mut events = Vec::new();
+        assert_eq!(poller.poll(&mut events, TIMEOUT).unwrap(), 1);
+        assert!(events[0].is_readable());
+
+        let (mut server, _) = listener.accept().unwrap();
+        server.set_nonblocking(true).unwrap();
+        assert_eq!(server.read(&mut [0; 4]).unwrap_err().kind(), ErrorKind::WouldBlock);
+    }
+
+    #[test]
+    fn poll_streams() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.set_nonblocking(true).unwrap();
+        server.set_nonblocking(true).unwrap();
+
+        let mut poller = Poller::new();
+        poller.register(&client, 1, Interest::READABLE | Interest::WRITABLE).unwrap();
+        poller.register(&server, 2, Interest::READABLE).unwrap();
+        assert_eq!(
+            poller.register(&server, 3, Interest::READABLE).unwrap_err().kind(),
+            ErrorKind::AlreadyExists
+        );
+
+        // Only the client can write, nothing has been sent yet.
+        let mut events = Vec::new();
+        assert_eq!(poller.poll(&mut events, TIMEOUT).unwrap(), 1);
+        assert_eq!(events[0].token(), 1);
+        assert!(events[0].is_writable() && !events[0].is_readable());
+
+        client.write_all(b"ping").unwrap();
+        poller.reregister(&client, 1, Interest::READABLE).unwrap();
+        assert_eq!(poller.poll(&mut events, TIMEOUT).unwrap(), 1);
+        assert_eq!(events[0].token(), 2);
+        assert!(events[0].is_readable());
+        let mut buf = [0; 4];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"ping");
+
+        // Nothing is ready anymore, a zero timeout returns right away.
+        assert_eq!(poller.poll(&mut events, Some(Duration::from_secs(0))).unwrap(), 0);
+        assert!(events.is_empty());
+
+        drop(client);
+        assert_eq!(poller.poll(&mut events, TIMEOUT).unwrap(), 1);
+        assert!(events[0].is_readable());
+        assert_eq!(server.read(&mut buf).unwrap(), 0);
+
+        poller.deregister(&server).unwrap();
+        assert_eq!(poller.deregister(&server).unwrap_err().kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn poll_udp() {
+        let a = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let b = UdpSocket::bind("127.0.0.1:0").unwrap();
+        b.set_nonblocking(true).unwrap();
+        assert_eq!(b.recv(&mut [0; 4]).unwrap_err().kind(), ErrorKind::WouldBlock);
+
+        let mut poller = Poller::new();
+        poller.register(&b, 7, Interest::READABLE).unwrap();
+        a.send_to(b"pong", b.local_addr().unwrap()).unwrap();
+
+        let mut events = Vec::new();
+        assert_eq!(poller.poll(&mut events, TIMEOUT).unwrap(), 1);
+        assert_eq!(events[0].token(), 7);
+        let mut buf = [0; 4];
+        assert_eq!(b.recv(&mut buf).unwrap(), 4);
+        assert_eq!(&buf, b"pong");
+    }
+
+    #[test]
+    fn configure_after_first_use() {
+        let _listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let err = configure(SocketConfig::default()).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+}
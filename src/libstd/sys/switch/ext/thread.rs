@@ -0,0 +1,165 @@
+//! Switch-specific extensions to primitives in the `std::thread` module.
+
+#![unstable(feature = "switch_ext", issue = "none")]
+
+use crate::io;
+use crate::sys::thread as imp;
+use crate::sys_common::{AsInner, AsInnerMut};
+use crate::thread::{self, Builder, JoinHandle};
+use crate::time::Duration;
+
+/// The nn::os object behind a thread spawned by std.
+pub type RawThread = *mut nnsdk::os::ThreadType;
+
+/// A kernel handle, as used by the `svc` calls.
+pub type RawHandle = u32;
+
+/// The highest priority a thread can be given. Lower values mean higher
+/// priority.
+pub const HIGHEST_PRIORITY: i32 = imp::HIGHEST_PRIORITY;
+
+/// The lowest priority a thread can be given.
+pub const LOWEST_PRIORITY: i32 = imp::LOWEST_PRIORITY;
+
+/// The priority threads are spawned with unless told otherwise. This is the
+/// same priority the game's main thread runs at.
+pub const DEFAULT_PRIORITY: i32 = imp::DEFAULT_PRIORITY;
+
+/// Switch-specific extensions to [`thread::Builder`].
+///
+/// [`thread::Builder`]: ../../../../std/thread/struct.Builder.html
+pub trait BuilderExt {
+    /// Sets the priority of the new thread, between [`HIGHEST_PRIORITY`] and
+    /// [`LOWEST_PRIORITY`]. Lower values mean higher priority.
+    ///
+    /// Spawning fails with [`InvalidInput`] if the priority is out of range.
+    ///
+    /// [`HIGHEST_PRIORITY`]: constant.HIGHEST_PRIORITY.html
+    /// [`LOWEST_PRIORITY`]: constant.LOWEST_PRIORITY.html
+    /// [`InvalidInput`]: ../../../../std/io/enum.ErrorKind.html#variant.InvalidInput
+    fn priority(self, priority: i32) -> Self;
+
+    /// Sets the core the new thread prefers to run on.
+    fn ideal_core(self, core: i32) -> Self;
+
+    /// Restricts the new thread to the cores set in `mask`, where bit `n`
+    /// stands for core `n`.
+    ///
+    /// If an ideal core is set as well, it has to be part of the mask.
+    fn affinity_mask(self, mask: u64) -> Self;
+
+    /// Runs the new thread on the given stack instead of allocating one.
+    ///
+    /// The memory has to be aligned to and a multiple of the page size
+    /// (0x1000 bytes), otherwise spawning fails with [`InvalidInput`]. Any
+    /// [`stack_size`] set on the builder is ignored.
+    ///
+    /// [`InvalidInput`]: ../../../../std/io/enum.ErrorKind.html#variant.InvalidInput
+    /// [`stack_size`]: ../../../../std/thread/struct.Builder.html#method.stack_size
+    fn stack_memory(self, stack: &'static mut [u8]) -> Self;
+}
+
+impl BuilderExt for Builder {
+    fn priority(mut self, priority: i32) -> Self {
+        self.as_inner_mut().priority = Some(priority);
+        self
+    }
+
+    fn ideal_core(mut self, core: i32) -> Self {
+        self.as_inner_mut().ideal_core = Some(core);
+        self
+    }
+
+    fn affinity_mask(mut self, mask: u64) -> Self {
+        self.as_inner_mut().affinity_mask = Some(mask);
+        self
+    }
+
+    fn stack_memory(mut self, stack: &'static mut [u8]) -> Self {
+        self.as_inner_mut().stack = Some((stack.as_mut_ptr(), stack.len()));
+        self
+    }
+}
+
+/// Switch-specific extensions to [`thread::JoinHandle`].
+///
+/// [`thread::JoinHandle`]: ../../../../std/thread/struct.JoinHandle.html
+pub trait JoinHandleExt<T>: Sized {
+    /// Returns the nn::os thread object without taking ownership of it.
+    ///
+    /// The object belongs to the `JoinHandle` and is destroyed once the
+    /// thread has been joined, or once it has exited after the handle was
+    /// dropped. It must not be destroyed or waited on through the pointer.
+    fn as_raw_thread(&self) -> RawThread;
+
+    /// Returns the kernel handle of the thread, for `svc` calls that nn::os
+    /// has no wrapper for.
+    ///
+    /// Like the thread object, the handle stays owned by the `JoinHandle`
+    /// and must not be closed. This goes through `nn::os::GetThreadHandle`,
+    /// and fails with [`ErrorKind::Other`] if the running title doesn't link
+    /// it.
+    ///
+    /// [`ErrorKind::Other`]: ../../../../std/io/enum.ErrorKind.html#variant.Other
+    fn as_raw_handle(&self) -> io::Result<RawHandle>;
+
+    /// Waits at most `dur` for the thread to finish.
+    ///
+    /// If it does, this returns the same as [`JoinHandle::join`]. Otherwise
+    /// the handle is given back in `Err`, to try again later or to drop it
+    /// and let the thread run on detached.
+    ///
+    /// [`JoinHandle::join`]: ../../../../std/thread/struct.JoinHandle.html#method.join
+    fn join_timeout(self, dur: Duration) -> Result<thread::Result<T>, Self>;
+}
+
+impl<T> JoinHandleExt<T> for JoinHandle<T> {
+    fn as_raw_thread(&self) -> RawThread {
+        self.as_inner().as_raw()
+    }
+
+    fn as_raw_handle(&self) -> io::Result<RawHandle> {
+        self.as_inner().handle()
+    }
+
+    fn join_timeout(self, dur: Duration) -> Result<thread::Result<T>, Self> {
+        JoinHandle::join_timeout(self, dur)
+    }
+}
+
+/// Returns the number of the core the calling thread is currently running on.
+pub fn current_core() -> u32 {
+    imp::current_core()
+}
+
+/// Returns the number of cores threads of this process are allowed to run on.
+pub fn available_cores() -> usize {
+    imp::available_cores()
+}
+
+/// Installs a handler that reports overflowing the stack of a thread spawned
+/// by std, instead of leaving the overflow to crash as a bare data abort.
+///
+/// The handler goes through `nn::os::SetUserExceptionHandler`, which is
+/// process-wide: it replaces any handler the game or another plugin set up,
+/// and there is no way to chain to that one. Exceptions that aren't stack
+/// overflows are left to the default crash handling.
+///
+/// The handler lives in this plugin, so it must be removed with
+/// [`uninstall_stack_overflow_handler`] before the plugin is unloaded. std
+/// does so itself when it is torn down.
+///
+/// [`uninstall_stack_overflow_handler`]: fn.uninstall_stack_overflow_handler.html
+pub fn install_stack_overflow_handler() {
+    crate::sys::stack_overflow::install()
+}
+
+/// Removes the handler set by [`install_stack_overflow_handler`], leaving the
+/// process without a user exception handler.
+///
+/// Does nothing if the handler isn't installed.
+///
+/// [`install_stack_overflow_handler`]: fn.install_stack_overflow_handler.html
+pub fn uninstall_stack_overflow_handler() {
+    crate::sys::stack_overflow::uninstall()
+}
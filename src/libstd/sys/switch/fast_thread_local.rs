@@ -0,0 +1,478 @@
+#![unstable(feature = "thread_local_internals", issue = "none")]
+
+//! ELF TLS for modules loaded by nn::ro.
+//!
+//! The loader doesn't know about our TLS segment, so neither the kernel nor
+//! nn::os set up a block for it on any thread. Instead every access to a
+//! `#[thread_local]` goes through a TLS descriptor (we are always built as a
+//! position independent module), and `init` points all of our descriptors at
+//! `__skyline_tlsdesc_resolve`. That resolver lazily creates this thread's copy
+//! of the TLS image, keeps track of it in an nn::os TLS slot and returns its
+//! offset from the thread pointer, which is all the compiler-generated code
+//! needs.
+//!
+//! Once the block exists, the resolver reads the slot straight out of the
+//! nn::os `ThreadType` of the current thread, so that accessing a
+//! thread-local only costs a few loads. The block is only created (and
+//! destructors only registered) through Rust code.
+//!
+//! The same per-thread block also keeps the list of destructors registered
+//! through `register_dtor`. They are run when a std thread returns, or by the
+//! TLS slot destructor nn::os invokes for any other thread that exits.
+
+use crate::alloc::{alloc_zeroed, dealloc, Layout};
+use crate::cmp;
+use crate::mem;
+use crate::ptr;
+use crate::sys_common::thread_local::StaticKey;
+
+use nnsdk::os::ThreadType;
+
+type Dtors = Vec<(*mut u8, unsafe extern "C" fn(*mut u8))>;
+
+// The resolver reads `tls` directly, so it has to stay the first field.
+#[repr(C)]
+struct ThreadData {
+    tls: *mut u8,
+    dtors: Dtors,
+}
+
+// Bounds of the TLS template, see `link.T`. The offsets the compiler uses are
+// relative to its start. `__tls_align` holds the alignment of the PT_TLS
+// segment, which the linker script writes out for us since the program headers
+// themselves aren't part of the loaded image.
+extern "C" {
+    static __tls_start: u8;
+    static __tls_end: u8;
+    static __tdata_end: u8;
+    static __tls_align: usize;
+    static __text_start: u8;
+    static __dynamic_start: u8;
+}
+
+// Offset of our slot in `ThreadType`, see `find_slot_offset`. It lives next
+// to the resolver below. As long as it's zero the resolver always takes the
+// slow path.
+extern "C" {
+    static mut __skyline_tls_slot_offset: usize;
+}
+
+static THREAD_DATA: StaticKey = StaticKey::new(Some(thread_exit));
+
+// Left in the slot once a thread's block has been released, so that accessing
+// a thread-local afterwards fails loudly instead of allocating a block nobody
+// would free.
+const DESTROYED: usize = 1;
+
+// Where nn::os keeps the `ThreadType` of a thread in its thread-local region.
+const THREAD_TYPE_OFFSET: usize = 0x1f8;
+
+fn tls_layout() -> Layout {
+    unsafe {
+        let start = &__tls_start as *const u8 as usize;
+        let size = &__tls_end as *const u8 as usize - start;
+        Layout::from_size_align(cmp::max(size, 1), cmp::max(__tls_align, 16)).unwrap()
+    }
+}
+
+unsafe fn thread_data() -> *mut ThreadData {
+    let data = THREAD_DATA.get() as *mut ThreadData;
+    if data as usize == DESTROYED {
+        rtabort!("thread-local accessed after the thread's destructors ran");
+    }
+    if !data.is_null() {
+        return data;
+    }
+
+    let layout = tls_layout();
+    let tls = alloc_zeroed(layout);
+    if tls.is_null() {
+        rtabort!("failed to allocate thread-local storage");
+    }
+    let start = &__tls_start as *const u8;
+    let init_size = &__tdata_end as *const u8 as usize - start as usize;
+    ptr::copy_nonoverlapping(start, tls, init_size);
+
+    let data = Box::into_raw(box ThreadData { tls, dtors: Vec::new() });
+    THREAD_DATA.set(data as *mut u8);
+    data
+}
+
+// Called by the descriptor resolver with the offset of a variable inside the
+// TLS segment, returns its address for the current thread.
+#[no_mangle]
+unsafe extern "C" fn __skyline_tls_get_addr(offset: usize) -> *mut u8 {
+    (*thread_data()).tls.add(offset)
+}
+
+// TLS descriptors may only clobber x0. The fast path gets by with two scratch
+// registers and leaves the flags alone; the first access on a thread has to
+// create its block, so the slow path saves everything the Rust code it calls
+// into might touch.
+global_asm!(
+    "
+.section .bss.__skyline_tls_slot_offset, \"aw\", %nobits
+.hidden __skyline_tls_slot_offset
+.global __skyline_tls_slot_offset
+.p2align 3
+__skyline_tls_slot_offset:
+    .zero 8
+
+.section .text.__skyline_tlsdesc_resolve, \"ax\", %progbits
+.hidden __skyline_tlsdesc_resolve
+.global __skyline_tlsdesc_resolve
+.type __skyline_tlsdesc_resolve, %function
+.p2align 2
+__skyline_tlsdesc_resolve:
+    stp x1, x2, [sp, #-16]!
+    adrp x1, __skyline_tls_slot_offset
+    ldr x1, [x1, :lo12:__skyline_tls_slot_offset]
+    cbz x1, 1f
+    mrs x2, tpidrro_el0
+    ldr x2, [x2, #0x1f8]
+    ldr x2, [x2, x1]
+    cbz x2, 1f
+    tbnz x2, #0, 1f
+    ldr x2, [x2]
+    ldr x1, [x0, #8]
+    add x0, x2, x1
+    mrs x1, tpidr_el0
+    sub x0, x0, x1
+    ldp x1, x2, [sp], #16
+    ret
+
+1:
+    ldp x1, x2, [sp], #16
+    stp x29, x30, [sp, #-16]!
+    mov x29, sp
+    stp x1, x2, [sp, #-16]!
+    stp x3, x4, [sp, #-16]!
+    stp x5, x6, [sp, #-16]!
+    stp x7, x8, [sp, #-16]!
+    stp x9, x10, [sp, #-16]!
+    stp x11, x12, [sp, #-16]!
+    stp x13, x14, [sp, #-16]!
+    stp x15, x16, [sp, #-16]!
+    stp x17, x18, [sp, #-16]!
+    stp q0, q1, [sp, #-32]!
+    stp q2, q3, [sp, #-32]!
+    stp q4, q5, [sp, #-32]!
+    stp q6, q7, [sp, #-32]!
+    stp q8, q9, [sp, #-32]!
+    stp q10, q11, [sp, #-32]!
+    stp q12, q13, [sp, #-32]!
+    stp q14, q15, [sp, #-32]!
+    stp q16, q17, [sp, #-32]!
+    stp q18, q19, [sp, #-32]!
+    stp q20, q21, [sp, #-32]!
+    stp q22, q23, [sp, #-32]!
+    stp q24, q25, [sp, #-32]!
+    stp q26, q27, [sp, #-32]!
+    stp q28, q29, [sp, #-32]!
+    stp q30, q31, [sp, #-32]!
+    mrs x1, nzcv
+    stp x1, xzr, [sp, #-16]!
+
+    ldr x0, [x0, #8]
+    bl __skyline_tls_get_addr
+    mrs x1, tpidr_el0
+    sub x0, x0, x1
+
+    ldp x1, xzr, [sp], #16
+    msr nzcv, x1
+    ldp q30, q31, [sp], #32
+    ldp q28, q29, [sp], #32
+    ldp q26, q27, [sp], #32
+    ldp q24, q25, [sp], #32
+    ldp q22, q23, [sp], #32
+    ldp q20, q21, [sp], #32
+    ldp q18, q19, [sp], #32
+    ldp q16, q17, [sp], #32
+    ldp q14, q15, [sp], #32
+    ldp q12, q13, [sp], #32
+    ldp q10, q11, [sp], #32
+    ldp q8, q9, [sp], #32
+    ldp q6, q7, [sp], #32
+    ldp q4, q5, [sp], #32
+    ldp q2, q3, [sp], #32
+    ldp q0, q1, [sp], #32
+    ldp x17, x18, [sp], #16
+    ldp x15, x16, [sp], #16
+    ldp x13, x14, [sp], #16
+    ldp x11, x12, [sp], #16
+    ldp x9, x10, [sp], #16
+    ldp x7, x8, [sp], #16
+    ldp x5, x6, [sp], #16
+    ldp x3, x4, [sp], #16
+    ldp x1, x2, [sp], #16
+    ldp x29, x30, [sp], #16
+    ret
+"
+);
+
+extern "C" {
+    fn __skyline_tlsdesc_resolve();
+}
+
+const DT_NULL: i64 = 0;
+const DT_RELA: i64 = 7;
+const DT_RELASZ: i64 = 8;
+const DT_SYMTAB: i64 = 6;
+const R_AARCH64_TLSDESC: u32 = 1031;
+
+#[repr(C)]
+struct Dyn {
+    tag: i64,
+    val: u64,
+}
+
+#[repr(C)]
+struct Rela {
+    offset: u64,
+    info: u64,
+    addend: i64,
+}
+
+#[repr(C)]
+struct Sym {
+    name: u32,
+    info: u8,
+    other: u8,
+    shndx: u16,
+    value: u64,
+    size: u64,
+}
+
+/// Fills in all TLS descriptors of this module. This has to happen before any
+/// `#[thread_local]` is accessed: nothing can catch an access that happens
+/// before, since it jumps straight through the unpatched descriptor. So it is
+/// called from `__custom_init`, which nn::ro runs as the module's `DT_INIT`
+/// before any other code of ours.
+pub unsafe fn init() {
+    // We are linked at address 0, so the runtime address of the start of
+    // `.text` is the load bias.
+    let base = &__text_start as *const u8 as usize;
+
+    let mut rela = 0;
+    let mut rela_size = 0;
+    let mut symtab = 0;
+    let mut dynamic = &__dynamic_start as *const u8 as *const Dyn;
+    while (*dynamic).tag != DT_NULL {
+        match (*dynamic).tag {
+            DT_RELA => rela = base + (*dynamic).val as usize,
+            DT_RELASZ => rela_size = (*dynamic).val as usize,
+            DT_SYMTAB => symtab = base + (*dynamic).val as usize,
+            _ => {}
+        }
+        dynamic = dynamic.add(1);
+    }
+
+    if rela == 0 {
+        return;
+    }
+
+    let relocs =
+        crate::slice::from_raw_parts(rela as *const Rela, rela_size / mem::size_of::<Rela>());
+    for reloc in relocs {
+        if reloc.info as u32 != R_AARCH64_TLSDESC {
+            continue;
+        }
+
+        let sym = (reloc.info >> 32) as usize;
+        let value = if sym == 0 { 0 } else { (*(symtab as *const Sym).add(sym)).value };
+
+        let desc = (base + reloc.offset as usize) as *mut usize;
+        *desc = __skyline_tlsdesc_resolve as usize;
+        *desc.add(1) = (value as i64 + reloc.addend) as usize;
+    }
+
+    __skyline_tls_slot_offset = find_slot_offset();
+}
+
+// nn::os keeps the values of TLS slots in an array inside `ThreadType`, but its
+// layout isn't known to us. So the offset of our slot is looked for by storing
+// a couple of values in it and scanning the current thread's `ThreadType` for
+// them. It's the same for every thread, as is the place the thread-local region
+// keeps the `ThreadType`. If anything doesn't add up, zero is returned and the
+// resolver sticks to `GetTlsValue`.
+unsafe fn find_slot_offset() -> usize {
+    const MARKERS: [usize; 2] = [0x5eed_7150_0000_0000, 0xa112_8eaf_ffff_fff0];
+
+    let thread = nnsdk::os::GetCurrentThread();
+    let tlr: usize;
+    llvm_asm!("mrs $0, tpidrro_el0" : "=r"(tlr) ::: "volatile");
+    if *((tlr + THREAD_TYPE_OFFSET) as *const *mut ThreadType) != thread {
+        return 0;
+    }
+
+    let words = thread as *const usize;
+    let len = mem::size_of::<ThreadType>() / mem::size_of::<usize>();
+    let previous = THREAD_DATA.get();
+    let mut found = [None; 2];
+    for (found, &marker) in found.iter_mut().zip(MARKERS.iter()) {
+        THREAD_DATA.set(marker as *mut u8);
+        *found = (0..len).find(|&i| ptr::read_volatile(words.add(i)) == marker);
+    }
+    THREAD_DATA.set(previous);
+
+    match found {
+        [Some(a), Some(b)] if a == b => a * mem::size_of::<usize>(),
+        _ => 0,
+    }
+}
+
+pub unsafe fn register_dtor(t: *mut u8, dtor: unsafe extern "C" fn(*mut u8)) {
+    (*thread_data()).dtors.push((t, dtor));
+}
+
+/// Runs all destructors registered on the current thread and releases its TLS
+/// block. Called by std threads right before they exit.
+pub unsafe fn run_dtors() {
+    let data = THREAD_DATA.get() as *mut ThreadData;
+    if !data.is_null() && data as usize != DESTROYED {
+        destroy(data);
+    }
+}
+
+unsafe fn destroy(data: *mut ThreadData) {
+    // Destructors may well register new destructors or touch other
+    // thread-locals, so keep draining the list until it stays empty. As on
+    // every other platform, a thread-local can't be re-initialized after its
+    // destructor ran.
+    loop {
+        let dtors = mem::take(&mut (*data).dtors);
+        if dtors.is_empty() {
+            break;
+        }
+        for (ptr, dtor) in dtors {
+            dtor(ptr);
+        }
+    }
+
+    THREAD_DATA.set(DESTROYED as *mut u8);
+    let data = Box::from_raw(data);
+    dealloc(data.tls, tls_layout());
+}
+
+// Slot destructor for threads std didn't spawn, nn::os calls this once the
+// thread exits.
+unsafe extern "C" fn thread_exit(data: *mut u8) {
+    // Threads spawned by std already released their block.
+    if data as usize == DESTROYED {
+        return;
+    }
+    // The slot may already have been cleared by the time we run, but the
+    // destructors still need to find the block.
+    THREAD_DATA.set(data);
+    destroy(data as *mut ThreadData);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::register_dtor;
+    use crate::cell::{Cell, RefCell};
+    use crate::sync::atomic::{AtomicUsize, Ordering};
+    use crate::sync::mpsc::{channel, Sender};
+    use crate::thread;
+
+    #[test]
+    fn values_are_per_thread() {
+        #[thread_local]
+        static VALUE: Cell<u32> = Cell::new(7);
+        #[thread_local]
+        static ZEROED: Cell<[u64; 4]> = Cell::new([0; 4]);
+
+        assert_eq!(VALUE.get(), 7);
+        VALUE.set(8);
+        ZEROED.set([1; 4]);
+
+        // A new thread starts out with a fresh copy of the template, both for
+        // initialized data and for zeroed data.
+        thread::spawn(|| {
+            assert_eq!(VALUE.get(), 7);
+            assert_eq!(ZEROED.get(), [0; 4]);
+            VALUE.set(9);
+            ZEROED.set([2; 4]);
+        })
+        .join()
+        .unwrap();
+
+        assert_eq!(VALUE.get(), 8);
+        assert_eq!(ZEROED.get(), [1; 4]);
+    }
+
+    #[test]
+    fn overaligned_values() {
+        #[repr(align(128))]
+        struct Aligned(Cell<u8>);
+
+        #[thread_local]
+        static ALIGNED: Aligned = Aligned(Cell::new(1));
+
+        assert_eq!(&ALIGNED as *const Aligned as usize % 128, 0);
+        thread::spawn(|| {
+            assert_eq!(&ALIGNED as *const Aligned as usize % 128, 0);
+            assert_eq!(ALIGNED.0.get(), 1);
+        })
+        .join()
+        .unwrap();
+    }
+
+    #[test]
+    fn thread_locals_are_dropped_at_thread_exit() {
+        struct NotifyOnDrop(Sender<()>);
+
+        impl Drop for NotifyOnDrop {
+            fn drop(&mut self) {
+                self.0.send(()).unwrap();
+            }
+        }
+
+        thread_local!(static NOTIFY: RefCell<Option<NotifyOnDrop>> = RefCell::new(None));
+
+        let (tx, rx) = channel();
+        thread::spawn(move || {
+            NOTIFY.with(|notify| *notify.borrow_mut() = Some(NotifyOnDrop(tx)));
+        })
+        .join()
+        .unwrap();
+        rx.try_recv().unwrap();
+    }
+
+    #[test]
+    fn register_dtor_runs_on_the_exiting_thread() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "C" fn dtor(value: *mut u8) {
+            assert_eq!(value as usize, 42);
+            CALLS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        thread::spawn(|| unsafe {
+            register_dtor(42 as *mut u8, dtor);
+            register_dtor(42 as *mut u8, dtor);
+        })
+        .join()
+        .unwrap();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn register_dtor_from_a_dtor() {
+        static CALLS: AtomicUsize = AtomicUsize::new(0);
+
+        unsafe extern "C" fn second(_: *mut u8) {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        unsafe extern "C" fn first(_: *mut u8) {
+            CALLS.fetch_add(1, Ordering::SeqCst);
+            register_dtor(crate::ptr::null_mut(), second);
+        }
+
+        thread::spawn(|| unsafe { register_dtor(crate::ptr::null_mut(), first) })
+            .join()
+            .unwrap();
+        assert_eq!(CALLS.load(Ordering::SeqCst), 2);
+    }
+}
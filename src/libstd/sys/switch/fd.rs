@@ -9,6 +9,18 @@ use crate::sys_common::AsInner;
 
 use libc::{c_int, c_void, ssize_t};
 
+// The only descriptors there are belong to nn::socket, which has an `fcntl` of
+// its own that doesn't know the title's libc and takes FreeBSD's constants.
+extern "C" {
+    // nn::socket::Fcntl(int, int, ...)
+    #[link_name = "_ZN2nn6socket5FcntlEiiz"]
+    fn Fcntl(fd: c_int, cmd: c_int, ...) -> c_int;
+}
+
+const F_GETFL: c_int = 3;
+const F_SETFL: c_int = 4;
+const O_NONBLOCK: c_int = 4;
+
 #[derive(Debug)]
 pub struct FileDesc {
     fd: c_int,
@@ -154,26 +166,12 @@ impl FileDesc {
         Ok(())
     }
 
-    #[cfg(target_os = "linux")]
-    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
-        unsafe {
-            let v = nonblocking as c_int;
-            cvt(libc::ioctl(self.fd, libc::FIONBIO, &v))?;
-            Ok(())
-        }
-    }
-
-    #[cfg(not(target_os = "linux"))]
     pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
         unsafe {
-            let previous = cvt(libc::fcntl(self.fd, libc::F_GETFL))?;
-            let new = if nonblocking {
-                previous | libc::O_NONBLOCK
-            } else {
-                previous & !libc::O_NONBLOCK
-            };
+            let previous = cvt(Fcntl(self.fd, F_GETFL, 0))?;
+            let new = if nonblocking { previous | O_NONBLOCK } else { previous & !O_NONBLOCK };
             if new != previous {
-                cvt(libc::fcntl(self.fd, libc::F_SETFL, new))?;
+                cvt(Fcntl(self.fd, F_SETFL, new))?;
             }
             Ok(())
         }
@@ -1,17 +1,24 @@
 use crate::ffi::{OsString, CStr, OsStr};
 use crate::os::switch::ffi::OsStrExt;
+use crate::cell::UnsafeCell;
+use crate::cmp;
 use crate::fmt;
 use crate::hash::Hash;
 use crate::io::{self, IoSlice, IoSliceMut, SeekFrom};
-use crate::path::{Path, PathBuf};
-use crate::sys::time::{SystemTime, UNIX_EPOCH};
+use crate::path::{Component, Path, PathBuf};
+use crate::sys::result;
+use crate::sys::time::SystemTime;
 use crate::sys::unsupported;
 use crate::sync::atomic::{AtomicU64, Ordering};
+use crate::sync::Arc;
+use crate::sys_common::mutex::Mutex;
+use crate::sys_common::rwlock::RWLock;
 
-use nnsdk::fs::{FileHandle, DirectoryEntry as NinDirEntry};
+use nnsdk::fs::{DirectoryHandle, FileHandle, DirectoryEntry as NinDirEntry};
 use nnsdk::fs::DirectoryEntryType_DirectoryEntryType_Directory as NN_ENTRY_DIR;
 use nnsdk::fs::DirectoryEntryType_DirectoryEntryType_File as NN_ENTRY_FILE;
 use crate::ffi::CString;
+use crate::os::raw::c_char;
 
 macro_rules! r_try {
     ($expr:expr) => {
@@ -19,7 +26,6 @@ macro_rules! r_try {
             let rc = $expr;
             match rc {
                 0 => Ok(()),
-                0x202 => Err(io::Error::new(io::ErrorKind::NotFound, "the file was not found")),
                 rc => Err(io::Error::from_raw_os_error(rc as _))
             }
         }
@@ -30,16 +36,36 @@ macro_rules! r_try {
 pub struct FileAttr {
     size: AtomicU64,
     file_type: FileType,
-    append: bool,
+    times: Option<FileTimes>,
+}
+
+#[derive(Clone, Copy, Debug)]
+struct FileTimes {
+    created: SystemTime,
+    modified: SystemTime,
+    accessed: SystemTime,
 }
 
+// Directory entries are big (a full path's worth of name each), so they are
+// read in small batches rather than all at once.
+const READ_DIR_BATCH: usize = 16;
+
 pub struct ReadDir {
-    inner_iter: crate::vec::IntoIter<NinDirEntry>,
+    dir: Dir,
     root: PathBuf,
+    entries: Vec<NinDirEntry>,
+    next: usize,
+    end_of_stream: bool,
 }
 
+struct Dir(DirectoryHandle);
+
+unsafe impl Send for Dir {}
+unsafe impl Sync for Dir {}
+
 pub struct DirEntry {
     path: PathBuf,
+    name: OsString,
     file_attr: FileAttr,
 }
 
@@ -62,7 +88,7 @@ impl Clone for FileAttr {
         Self {
             size: AtomicU64::new(self.size.load(Ordering::SeqCst)),
             file_type: self.file_type,
-            append: false,
+            times: self.times,
         }
     }
 }
@@ -76,6 +102,20 @@ impl FileAttr {
         self.size.store(size, Ordering::SeqCst);
     }
 
+    // Other threads may be writing to the file at the same time, so this
+    // only ever grows the size we keep track of.
+    fn grow_size(&self, size: u64) {
+        let mut current = self.size();
+        while current < size {
+            let result =
+                self.size.compare_exchange_weak(current, size, Ordering::SeqCst, Ordering::SeqCst);
+            match result {
+                Ok(_) => break,
+                Err(actual) => current = actual,
+            }
+        }
+    }
+
     pub fn perm(&self) -> FilePermissions {
         FilePermissions { read_only: false }
     }
@@ -85,15 +125,22 @@ impl FileAttr {
     }
 
     pub fn modified(&self) -> io::Result<SystemTime> {
-        Ok(UNIX_EPOCH)
+        self.times().map(|times| times.modified)
     }
 
     pub fn accessed(&self) -> io::Result<SystemTime> {
-        Ok(UNIX_EPOCH)
+        self.times().map(|times| times.accessed)
     }
 
     pub fn created(&self) -> io::Result<SystemTime> {
-        Ok(UNIX_EPOCH)
+        self.times().map(|times| times.created)
+    }
+
+    fn times(&self) -> io::Result<FileTimes> {
+        self.times.ok_or_else(|| io::Error::new(
+            io::ErrorKind::Other,
+            "file timestamps are unsupported on this mount"
+        ))
     }
 }
 
@@ -139,26 +186,76 @@ impl Iterator for ReadDir {
     type Item = io::Result<DirEntry>;
 
     fn next(&mut self) -> Option<io::Result<DirEntry>> {
-        let val = self.inner_iter.next()?;
+        if self.next == self.entries.len() {
+            if self.end_of_stream {
+                return None;
+            }
+            if let Err(e) = self.fill() {
+                self.end_of_stream = true;
+                return Some(Err(e));
+            }
+            if self.entries.is_empty() {
+                self.end_of_stream = true;
+                return None;
+            }
+        }
+
+        let entry = &self.entries[self.next];
+        self.next += 1;
+        Some(self.entry(entry))
+    }
+}
+
+impl ReadDir {
+    // Replaces the current batch of entries with the next one, which is empty
+    // once the end of the directory has been reached.
+    fn fill(&mut self) -> io::Result<()> {
+        self.entries.clear();
+        self.next = 0;
+
+        let mut count: i64 = 0;
+        unsafe {
+            r_try!(nnsdk::fs::ReadDirectory(
+                &mut count,
+                self.entries.as_mut_ptr(),
+                self.dir.0,
+                READ_DIR_BATCH as i64
+            ))?;
+            self.entries.set_len(cmp::min(count as usize, READ_DIR_BATCH));
+        }
+        Ok(())
+    }
 
-        let len = unsafe { libc::strlen(val.name.as_ptr()) };
-        let path = self.root.join(Path::new(OsStr::from_bytes(&val.name[..len])));
+    fn entry(&self, entry: &NinDirEntry) -> io::Result<DirEntry> {
+        let len = entry.name.iter().position(|&c| c == 0).unwrap_or(entry.name.len());
+        let name = OsStr::from_bytes(&entry.name[..len]).to_os_string();
 
-        let file_type = match val.type_ as u32 {
+        let file_type = match entry.type_ as u32 {
             NN_ENTRY_DIR => FileType::Dir,
             NN_ENTRY_FILE => FileType::File,
-            _ => panic!("Invalid entry type in directory")
+            _ => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "invalid entry type in directory",
+                ));
+            }
         };
 
-        let file_attr = FileAttr {
-            size: AtomicU64::new(val.fileSize as u64),
-            file_type,
-            append: false,
-        };
+        Ok(DirEntry {
+            path: self.root.join(&name),
+            name,
+            file_attr: FileAttr {
+                size: AtomicU64::new(entry.fileSize as u64),
+                file_type,
+                times: None,
+            },
+        })
+    }
+}
 
-        Some(Ok(DirEntry {
-            path, file_attr
-        }))
+impl Drop for Dir {
+    fn drop(&mut self) {
+        unsafe { nnsdk::fs::CloseDirectory(self.0) }
     }
 }
 
@@ -168,14 +265,15 @@ impl DirEntry {
     }
 
     pub fn file_name(&self) -> OsString {
-        match self.path.file_name() {
-            Some(file_name) => file_name.to_os_string(),
-            None => panic!("Could not get file name of DirEntry")
-        }
+        self.name.clone()
     }
 
     pub fn metadata(&self) -> io::Result<FileAttr> {
-        Ok(self.file_attr.clone())
+        // Directory listings don't carry timestamps, so only query them once
+        // someone actually asks for the metadata.
+        let mut attr = self.file_attr.clone();
+        attr.times = file_times(&cstr(&self.path)?);
+        Ok(attr)
     }
 
     pub fn file_type(&self) -> io::Result<FileType> {
@@ -185,86 +283,149 @@ impl DirEntry {
 
 #[derive(Clone, Debug)]
 pub struct OpenOptions {
-    flags: u64,
+    // generic
+    read: bool,
+    write: bool,
+    append: bool,
     truncate: bool,
     create: bool,
     create_new: bool,
-    append: bool,
+    // system-specific
+    commit_on_sync: bool,
 }
 
+// nn::fs::OpenMode
 const READ_MODE: u64 = 1;
 const WRITE_MODE: u64 = 2;
-const APPEND_MODE: u64 = 4;
-
+// Lets writes extend the file. Unlike O_APPEND this has no say in where the
+// data ends up, so every writable file is opened with it.
+const ALLOW_APPEND_MODE: u64 = 4;
 
 impl OpenOptions {
     pub fn new() -> OpenOptions {
         OpenOptions {
-            flags: 0,
+            // generic
+            read: false,
+            write: false,
+            append: false,
             truncate: false,
             create: false,
             create_new: false,
-            append: false,
+            // system-specific
+            commit_on_sync: false,
         }
     }
 
     pub fn read(&mut self, read: bool) {
-        if read {
-            self.flags |= READ_MODE;
-        } else {
-            self.flags &= !READ_MODE;
-        }
+        self.read = read;
     }
     pub fn write(&mut self, write: bool) {
-        if write {
-            self.flags |= WRITE_MODE;
-            self.flags |= APPEND_MODE;
-        } else {
-            self.flags &= !WRITE_MODE;
-        }
+        self.write = write;
     }
     pub fn append(&mut self, append: bool) {
-        if append {
-            self.write(true);
-        }
-
         self.append = append;
     }
     pub fn truncate(&mut self, truncate: bool) {
         self.truncate = truncate;
     }
-
     pub fn create(&mut self, create: bool) {
         self.create = create;
     }
-
     pub fn create_new(&mut self, create_new: bool) {
         self.create_new = create_new;
     }
+
+    pub fn commit_on_sync(&mut self, commit_on_sync: bool) {
+        self.commit_on_sync = commit_on_sync;
+    }
+
+    // Same rules as `open(2)` enforces on unix.
+    fn get_open_mode(&self) -> io::Result<u64> {
+        let mode = match (self.read, self.write, self.append) {
+            (true, false, false) => READ_MODE,
+            (false, true, false) | (false, _, true) => WRITE_MODE | ALLOW_APPEND_MODE,
+            (true, true, false) | (true, _, true) => READ_MODE | WRITE_MODE | ALLOW_APPEND_MODE,
+            (false, false, false) => {
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, "no access mode set"));
+            }
+        };
+
+        match (self.write, self.append) {
+            (true, false) => {}
+            (false, false) => {
+                if self.truncate || self.create || self.create_new {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "creating or truncating a file requires write access",
+                    ));
+                }
+            }
+            (_, true) => {
+                if self.truncate && !self.create_new {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "a file can't be truncated and appended to at the same time",
+                    ));
+                }
+            }
+        }
+
+        Ok(mode)
+    }
 }
 
-pub struct File {
-    inner: FileHandle,
+pub struct File(Arc<Inner>);
+
+// Everything a file shares with its clones, much like an open file description
+// on unix: the handle, the cursor and what we know about the file's size. The
+// handle is closed once the last clone is gone.
+struct Inner {
+    handle: UnsafeCell<FileHandle>,
     pos: AtomicU64,
-    attr: FileAttr
+    attr: FileAttr,
+    append: bool,
+    commit: Option<SaveCommit>,
+}
+
+// Files opened with `commit_on_sync` commit their mount on `fsync`. nn::fs
+// refuses to commit while any file on the mount is open for writing, so our own
+// handle has to be closed for the commit and reopened afterwards. Every other
+// use of the handle holds the lock for reading.
+//
+// If reopening fails the file is left without a handle, and `reopen_error`
+// keeps the error so every later use of the file reports it.
+struct SaveCommit {
+    mount: CString,
+    path: CString,
+    mode: u64,
+    lock: RWLock,
+    reopen_error: UnsafeCell<Option<i32>>,
 }
 
-unsafe impl Send for File {}
+unsafe impl Send for Inner {}
+unsafe impl Sync for Inner {}
 
-impl crate::ops::Drop for File {
+impl crate::ops::Drop for Inner {
     fn drop(&mut self) {
-        if self.inner.handle.is_null() {
-            return;
+        let handle = *self.handle.get_mut();
+        if !handle.handle.is_null() {
+            // Writes aren't flushed as they happen, and nn::fs aborts when a
+            // file with unflushed writes is closed. Errors can't be reported
+            // from here, as with `close(2)` on unix.
+            unsafe {
+                let _ = nnsdk::fs::FlushFile(handle);
+                nnsdk::fs::CloseFile(handle);
+            }
         }
-        unsafe {
-            nnsdk::fs::CloseFile(
-                self.inner
-            );
+        if let Some(commit) = &self.commit {
+            unsafe { commit.lock.destroy() }
         }
     }
 }
 
+// Converts a path for nn::fs, which only understands absolute paths.
 fn cstr(path: &Path) -> io::Result<CString> {
+    let path = crate::sys::os::absolute(path)?;
     CString::new(
         path.to_str()
             .ok_or(io::Error::from(io::ErrorKind::InvalidInput))?
@@ -272,146 +433,210 @@ fn cstr(path: &Path) -> io::Result<CString> {
     ).map_err(io::Error::from)
 }
 
-macro_rules! ret_if_null {
-    ($expr:expr) => {
-        if ($expr.handle).is_null() {
-            return Err(io::Error::new(
-                io::ErrorKind::InvalidInput,
-                "Cannot treat directory as file"
-            ))
-        }
-    };
-}
-
 impl File {
     pub fn open(path: &Path, opts: &OpenOptions) -> io::Result<File> {
-        if !path.is_absolute() {
-            return Err(io::Error::new(io::ErrorKind::NotFound, "Path must be absolute"));
-        }
-
+        let mode = opts.get_open_mode()?;
         let path = cstr(path)?;
 
-        let mut inner = FileHandle { handle: 0 as _ };
-        
         let mut entry_type = 0u32;
-
-        unsafe {
-            match nnsdk::fs::GetEntryType(&mut entry_type, path.as_ptr() as _) {
-                0 => {
-                    if opts.create_new {
-                        return Err(io::Error::new(io::ErrorKind::AlreadyExists, path.to_str().unwrap()));
-                    }
-                },
-                // Path does not exist
-                514 if opts.create => {
-                    nnsdk::fs::CreateFile(path.as_ptr() as _, 0);
-                    entry_type = NN_ENTRY_FILE;
-                },
-                result => return Err(io::Error::new(io::ErrorKind::Other, format!("Result code: {}", result))),
-            };
+        let rc = unsafe { nnsdk::fs::GetEntryType(&mut entry_type, path.as_ptr() as _) as i32 };
+        match rc {
+            0 if opts.create_new => {
+                return Err(io::Error::from_raw_os_error(result::FS_PATH_ALREADY_EXISTS));
+            }
+            0 => {}
+            result::FS_PATH_NOT_FOUND if opts.create || opts.create_new => {
+                unsafe { r_try!(nnsdk::fs::CreateFile(path.as_ptr() as _, 0))? };
+                entry_type = NN_ENTRY_FILE;
+            }
+            rc => return Err(io::Error::from_raw_os_error(rc)),
         }
-        
-        let file_type = match entry_type {
-            NN_ENTRY_DIR => FileType::Dir,
-            NN_ENTRY_FILE => FileType::File,
-            _ => panic!("Invalid entry type in directory")
-        };
 
-        if let FileType::Dir = file_type {
-            let attr = FileAttr {
-                size: AtomicU64::new(0),
-                file_type,
-                append: false,
-            };
-            return Ok(File {
-                inner, pos: AtomicU64::new(0), attr
-            })
+        match entry_type {
+            NN_ENTRY_FILE => {}
+            // nn::fs can't open directories, but std lets them be opened for
+            // reading to get at their metadata. Everything else fails on the
+            // null handle.
+            NN_ENTRY_DIR if mode == READ_MODE => {
+                return Ok(File(Arc::new(Inner {
+                    handle: UnsafeCell::new(FileHandle { handle: 0 as _ }),
+                    pos: AtomicU64::new(0),
+                    attr: FileAttr {
+                        size: AtomicU64::new(0),
+                        file_type: FileType::Dir,
+                        times: file_times(&path),
+                    },
+                    append: false,
+                    commit: None,
+                })));
+            }
+            NN_ENTRY_DIR => {
+                return Err(io::Error::new(io::ErrorKind::Other, "is a directory"));
+            }
+            _ => {
+                return Err(io::Error::new(io::ErrorKind::InvalidData, "invalid entry type"));
+            }
         }
 
-        unsafe { 
-            r_try!(
-                nnsdk::fs::OpenFile(
-                    &mut inner,
-                    path.as_ptr() as _,
-                    opts.flags as _
-                )
-            )?;
+        let mount = if opts.commit_on_sync { Some(mount_name(&path)?) } else { None };
+
+        let mut inner = FileHandle { handle: 0 as _ };
+        unsafe {
+            r_try!(nnsdk::fs::OpenFile(&mut inner, path.as_ptr() as _, mode as _))?;
         }
 
-        if inner.handle.is_null() {
-            Err(io::Error::new(io::ErrorKind::NotFound, "Returned file handle was null"))
-        } else {
+        let times = file_times(&path);
+        let commit = mount.map(|mount| SaveCommit {
+            mount,
+            path,
+            mode,
+            lock: RWLock::new(),
+            reopen_error: UnsafeCell::new(None),
+        });
+
+        // From here on the handle is closed by `File`'s destructor on error.
+        let file = File(Arc::new(Inner {
+            handle: UnsafeCell::new(inner),
+            pos: AtomicU64::new(0),
+            attr: FileAttr {
+                size: AtomicU64::new(0),
+                file_type: FileType::File,
+                times,
+            },
+            append: opts.append,
+            commit,
+        }));
+
+        let size = file.with_handle(|handle| unsafe {
             let mut size = 0;
+            r_try!(nnsdk::fs::GetFileSize(&mut size, handle))?;
+            Ok(size)
+        })?;
+        file.0.attr.set_size(size as u64);
 
-            unsafe {
-                r_try!(nnsdk::fs::GetFileSize(&mut size, inner))?;
-            }
-            
-            let pos = if opts.append {
-                AtomicU64::new(size as u64)
-            } else {
-                AtomicU64::new(0)
-            };
+        if opts.truncate {
+            file.truncate(0)?;
+        }
 
-            let mut attr = stat_internal(&path, size as _)?;
+        Ok(file)
+    }
 
-            if opts.flags & APPEND_MODE != 0 {
-                attr.append = true;
-            }
+    pub fn file_attr(&self) -> io::Result<FileAttr> {
+        Ok(self.0.attr.clone())
+    }
 
-            let file = File { inner, pos, attr };
+    // Runs `f` with the file's handle, which stays open until `f` returns.
+    fn with_handle<T>(&self, f: impl FnOnce(FileHandle) -> io::Result<T>) -> io::Result<T> {
+        let commit = match &self.0.commit {
+            Some(commit) => commit,
+            None => return f(self.handle()?),
+        };
+        unsafe {
+            commit.lock.read();
+            let result = self.handle().and_then(f);
+            commit.lock.read_unlock();
+            result
+        }
+    }
 
-            if opts.truncate {
-                file.truncate(0)?;
+    fn handle(&self) -> io::Result<FileHandle> {
+        let handle = unsafe { *self.0.handle.get() };
+        if handle.handle.is_null() {
+            if let Some(commit) = &self.0.commit {
+                if let Some(rc) = unsafe { *commit.reopen_error.get() } {
+                    return Err(io::Error::from_raw_os_error(rc));
+                }
             }
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "Cannot treat directory as file"
+            ))
+        }
+        Ok(handle)
+    }
 
-            Ok(file)
+    pub fn fsync(&self) -> io::Result<()> {
+        let commit = match &self.0.commit {
+            Some(commit) => commit,
+            None => return self.datasync(),
+        };
+        unsafe {
+            commit.lock.write();
+            let result = self.commit(commit);
+            commit.lock.write_unlock();
+            result
         }
     }
 
-    pub fn file_attr(&self) -> io::Result<FileAttr> {
-        Ok(self.attr.clone())
+    // Must be called with the commit lock held for writing.
+    unsafe fn commit(&self, commit: &SaveCommit) -> io::Result<()> {
+        // Fails if an earlier commit couldn't reopen the file.
+        let handle = self.handle()?;
+        r_try!(nnsdk::fs::FlushFile(handle))?;
+        nnsdk::fs::CloseFile(handle);
+        (*self.0.handle.get()).handle = 0 as _;
+
+        let committed = r_try!(CommitSaveData(commit.mount.as_ptr()));
+        // The file has to stay usable whether or not the commit went through.
+        self.reopen(commit)?;
+        committed
     }
 
-    pub fn fsync(&self) -> io::Result<()> {
-        ret_if_null!(self.inner);
-        unsafe { r_try!(nnsdk::fs::FlushFile(self.inner)) }
+    // Must be called with the commit lock held for writing, and the handle
+    // closed.
+    unsafe fn reopen(&self, commit: &SaveCommit) -> io::Result<()> {
+        let handle = &mut *self.0.handle.get();
+        let rc = nnsdk::fs::OpenFile(handle, commit.path.as_ptr() as _, commit.mode as _);
+        if rc != 0 {
+            handle.handle = 0 as _;
+            *commit.reopen_error.get() = Some(rc as i32);
+        }
+        r_try!(rc)
     }
 
     pub fn datasync(&self) -> io::Result<()> {
-        self.fsync()
+        self.with_handle(|handle| unsafe { r_try!(nnsdk::fs::FlushFile(handle)) })
     }
 
     pub fn truncate(&self, size: u64) -> io::Result<()> {
-        ret_if_null!(self.inner);
-        let rc = unsafe {
-            nnsdk::fs::SetFileSize(self.inner, size as _)
-        };
+        self.with_handle(|handle| {
+            let rc = unsafe {
+                nnsdk::fs::SetFileSize(handle, size as _)
+            };
 
-        self.attr.set_size(size);
+            self.0.attr.set_size(size);
 
-        r_try!(rc)
+            r_try!(rc)
+        })
     }
 
     pub fn read(&self, buf: &mut [u8]) -> io::Result<usize> {
-        ret_if_null!(self.inner);
-        let mut out_size = 0;
-        let rc = unsafe {
-            nnsdk::fs::ReadFile1(
-                &mut out_size,
-                self.inner,
-                self.pos() as _,
-                buf.as_ptr() as _,
-                buf.len() as _
-            )
-        };
+        let pos = self.pos();
+        let read = self.read_at(buf, pos)?;
+        self.0.pos.store(pos + read as u64, Ordering::SeqCst);
+        Ok(read)
+    }
 
-        if rc == 0 {
-            self.pos.fetch_add(out_size, Ordering::SeqCst);
+    pub fn read_at(&self, buf: &mut [u8], offset: u64) -> io::Result<usize> {
+        self.with_handle(|handle| {
+            // Reading at or past the end of the file is an error for nn::fs,
+            // but just the end of the file for std.
+            if offset >= self.0.attr.size() {
+                return Ok(0);
+            }
+
+            let mut out_size = 0;
+            unsafe {
+                r_try!(nnsdk::fs::ReadFile1(
+                    &mut out_size,
+                    handle,
+                    offset as _,
+                    buf.as_ptr() as _,
+                    buf.len() as _
+                ))?;
+            }
             Ok(out_size as usize)
-        } else {
-            Err(io::Error::from_raw_os_error(rc as _))
-        }
+        })
     }
 
     pub fn read_vectored(&self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
@@ -427,31 +652,31 @@ impl File {
     }
 
     pub fn write(&self, buf: &[u8]) -> io::Result<usize> {
-        ret_if_null!(self.inner);
-
-        if !self.attr.append && self.pos.load(Ordering::SeqCst) + buf.len() as u64 > self.attr.size.load(Ordering::SeqCst) {
-            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, format!("The length of the buffer ({}) is larger than the size of the file ({}). Consider using the Append flag.", buf.len(), self.pos.load(Ordering::SeqCst))));
-        }
-
-        let rc = unsafe {
-            nnsdk::fs::WriteFile(
-                self.inner,
-                self.pos() as _,
-                buf.as_ptr() as _,
-                buf.len() as u64,
-                &nnsdk::fs::WriteOption { flags: 1 }
-            )
-        };
+        // Like O_APPEND, every write goes to the end of the file no matter
+        // where the cursor was moved to.
+        let pos = if self.0.append { self.0.attr.size() } else { self.pos() };
+        let written = self.write_at(buf, pos)?;
+        self.0.pos.store(pos + written as u64, Ordering::SeqCst);
+        Ok(written)
+    }
 
-        if rc == 0 {
-            self.pos.fetch_add(buf.len() as u64, Ordering::SeqCst);
-            if self.pos() > self.attr.size() {
-                self.attr.set_size(self.pos());
+    pub fn write_at(&self, buf: &[u8], offset: u64) -> io::Result<usize> {
+        self.with_handle(|handle| {
+            unsafe {
+                r_try!(nnsdk::fs::WriteFile(
+                    handle,
+                    offset as _,
+                    buf.as_ptr() as _,
+                    buf.len() as u64,
+                    // Flushing every write makes for very slow SD card
+                    // access, it's left to `flush`, `sync_all` and drop.
+                    &nnsdk::fs::WriteOption { flags: 0 }
+                ))?;
             }
+
+            self.0.attr.grow_size(offset + buf.len() as u64);
             Ok(buf.len())
-        } else {
-            Err(io::Error::from_raw_os_error(rc as _))
-        }
+        })
     }
 
     pub fn write_vectored(&self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
@@ -467,49 +692,43 @@ impl File {
     }
 
     pub fn flush(&self) -> io::Result<()> {
-        self.fsync()
+        self.datasync()
     }
 
     fn pos(&self) -> u64 {
-        self.pos.load(Ordering::SeqCst)
+        self.0.pos.load(Ordering::SeqCst)
     }
 
     pub fn seek(&self, pos: SeekFrom) -> io::Result<u64> {
-        match pos {
+        let (base, offset) = match pos {
             SeekFrom::Start(offset) => {
-                self.pos.store(offset, Ordering::SeqCst);
-            },
-            SeekFrom::Current(offset) => {
-                let pos = (self.pos.load(Ordering::SeqCst) as i64) + offset;
-                if pos < 0 {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "Attempted to seek to an invalid or negative offset"
-                    ))
-                }
-                self.pos.store(pos as u64, Ordering::SeqCst);
-            },
-            SeekFrom::End(offset) => {
-                if offset > 0 || (-offset as u64) > self.attr.size() {
-                    return Err(io::Error::new(
-                        io::ErrorKind::InvalidInput,
-                        "Attempted to seek to an invalid or negative offset"
-                    ))
-                }
-                self.pos.store(self.attr.size() + (-offset as u64), Ordering::SeqCst);
-            },
+                self.0.pos.store(offset, Ordering::SeqCst);
+                return Ok(offset);
+            }
+            SeekFrom::Current(offset) => (self.pos(), offset),
+            SeekFrom::End(offset) => (self.0.attr.size(), offset),
         };
 
-        Ok(self.pos.load(Ordering::SeqCst))
+        // Seeking past the end is fine, the file grows once written to.
+        let pos = if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.wrapping_neg() as u64)
+        };
+        match pos {
+            Some(pos) => {
+                self.0.pos.store(pos, Ordering::SeqCst);
+                Ok(pos)
+            }
+            None => Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "invalid seek to a negative or overflowing position",
+            )),
+        }
     }
 
     pub fn duplicate(&self) -> io::Result<File> {
-        // This feels super wrong and will probably break something
-        Ok(File {
-            inner: self.inner.clone(),
-            pos: AtomicU64::new(self.pos()),
-            attr: self.attr.clone()
-        })
+        Ok(File(self.0.clone()))
     }
 
     pub fn set_permissions(&self, _perm: FilePermissions) -> io::Result<()> {
@@ -542,49 +761,24 @@ impl fmt::Debug for File {
 }
 
 pub fn readdir(path: &Path) -> io::Result<ReadDir> {
-    let path_buf = path.to_path_buf();
+    let root = path.to_path_buf();
     let path = cstr(path)?;
 
-    let mut dir_handle = nnsdk::fs::DirectoryHandle { handle: 0 as *mut _ };
-    let mut count: i64 = 0;
-
+    let mut handle = DirectoryHandle { handle: 0 as *mut _ };
     unsafe {
-        r_try!(
-            nnsdk::fs::OpenDirectory(
-                &mut dir_handle as _,
-                path.as_ptr() as _,
-                nnsdk::fs::OpenDirectoryMode_OpenDirectoryMode_All as _
-            )
-        )?;
-
-        r_try!(
-            nnsdk::fs::GetDirectoryEntryCount(
-                &mut count as _,
-                dir_handle
-            )
-        )?;
-    }
-
-    let mut entries: Vec<NinDirEntry> = Vec::with_capacity(count as usize);
-
-    unsafe {
-        entries.set_len(count as usize);
-
-        r_try!(
-            nnsdk::fs::ReadDirectory(
-                &mut count,
-                entries.as_mut_ptr(),
-                dir_handle,
-                count
-            )
-        )?;
-
-        nnsdk::fs::CloseDirectory(dir_handle);
+        r_try!(nnsdk::fs::OpenDirectory(
+            &mut handle,
+            path.as_ptr() as _,
+            nnsdk::fs::OpenDirectoryMode_OpenDirectoryMode_All as _
+        ))?;
     }
 
     Ok(ReadDir {
-        inner_iter: entries.into_iter(),
-        root: path_buf
+        dir: Dir(handle),
+        root,
+        entries: Vec::with_capacity(READ_DIR_BATCH),
+        next: 0,
+        end_of_stream: false,
     })
 }
 
@@ -615,28 +809,53 @@ pub fn set_perm(_p: &Path, _perm: FilePermissions) -> io::Result<()> {
     Ok(())
 }
 
+// Not every title links these, see `sys::weak`.
+weak! {
+    #[link_name = "_ZN2nn2fs15DeleteDirectoryEPKc"]
+    fn DeleteDirectory(*const c_char) -> u32
+}
+weak! {
+    #[link_name = "_ZN2nn2fs26DeleteDirectoryRecursivelyEPKc"]
+    fn DeleteDirectoryRecursively(*const c_char) -> u32
+}
+
+fn unsupported_by_title() -> io::Error {
+    io::Error::new(io::ErrorKind::Other, "unsupported by this title")
+}
+
 pub fn rmdir(path: &Path) -> io::Result<()> {
     let path = cstr(path)?;
-
-    if (nnsdk::fs::DeleteDirectory as *const ()).is_null() {
-        panic!("DeleteDirectory is null");
-    }
+    let delete = DeleteDirectory.get().ok_or_else(unsupported_by_title)?;
 
     unsafe {
-        r_try!(nnsdk::fs::DeleteDirectory(path.as_ptr() as _))
+        r_try!(delete(path.as_ptr()))
     }
 }
 
 pub fn remove_dir_all(path: &Path) -> io::Result<()> {
+    let delete = match DeleteDirectoryRecursively.get() {
+        Some(delete) => delete,
+        None => return remove_dir_all_recursive(path),
+    };
     let path = cstr(path)?;
 
-    if (nnsdk::fs::DeleteDirectoryRecursively as *const ()).is_null() {
-        panic!("DeleteDirectoryRecursively is null");
+    unsafe {
+        r_try!(delete(path.as_ptr()))
     }
+}
 
-    unsafe {
-        r_try!(nnsdk::fs::DeleteDirectoryRecursively(path.as_ptr() as _))
+fn remove_dir_all_recursive(path: &Path) -> io::Result<()> {
+    // nn::fs doesn't like directories changing while they are being read, so
+    // finish reading (and close the directory) before deleting anything.
+    let entries = readdir(path)?.collect::<io::Result<Vec<_>>>()?;
+    for entry in entries {
+        if entry.file_type()?.is_dir() {
+            remove_dir_all_recursive(&entry.path())?;
+        } else {
+            unlink(&entry.path())?;
+        }
     }
+    rmdir(path)
 }
 
 pub fn readlink(_p: &Path) -> io::Result<PathBuf> {
@@ -672,21 +891,33 @@ fn get_entry_type(cstr: &CStr) -> io::Result<FileType> {
     }
 }
 
-fn stat_internal(cstr: &CStr, size: u64) -> io::Result<FileAttr> {
-    let file_type = get_entry_type(cstr)?;
+// The only way to get at timestamps is a debug API, which retail titles don't
+// necessarily link, so it's looked up at runtime (see `sys::weak`).
+weak! {
+    #[link_name = "_ZN2nn2fs24GetFileTimeStampForDebugEPNS0_13FileTimeStampEPKc"]
+    fn GetFileTimeStampForDebug(*mut nnsdk::fs::FileTimeStamp, *const c_char) -> u32
+}
+
+// Timestamps are only tracked by some mounts (most notably the SD card), so a
+// failure here just means the mount doesn't support them, or the title doesn't
+// have the API.
+fn file_times(cstr: &CStr) -> Option<FileTimes> {
+    let get_time_stamp = GetFileTimeStampForDebug.get()?;
+    let mut stamp: nnsdk::fs::FileTimeStamp = unsafe { crate::mem::zeroed() };
+    let rc = unsafe { get_time_stamp(&mut stamp, cstr.as_ptr()) };
+
+    if rc != 0 {
+        return None;
+    }
 
-    Ok(FileAttr {
-        size: AtomicU64::new(size),
-        file_type,
-        append: false,
+    Some(FileTimes {
+        created: SystemTime::from_posix_time(stamp.create),
+        modified: SystemTime::from_posix_time(stamp.modify),
+        accessed: SystemTime::from_posix_time(stamp.access),
     })
 }
 
 pub fn stat(path: &Path) -> io::Result<FileAttr> {
-    if !path.is_absolute() {
-        return Err(io::Error::new(io::ErrorKind::NotFound, "Path must be absolute"));
-    }
-
     let path = cstr(path)?;
     
     let file_type = match get_entry_type(&path)? {
@@ -724,7 +955,7 @@ pub fn stat(path: &Path) -> io::Result<FileAttr> {
         FileType::Dir => Ok(AtomicU64::new(0)),
     };
 
-    Ok(FileAttr { size: size?, file_type, append: false })
+    Ok(FileAttr { size: size?, file_type, times: file_times(&path) })
     //File::open(path, &OpenOptions::new())?.file_attr()
 }
 
@@ -732,13 +963,783 @@ pub fn lstat(path: &Path) -> io::Result<FileAttr> {
     stat(path)
 }
 
-pub fn canonicalize(_p: &Path) -> io::Result<PathBuf> {
-    unsupported()
+// There are no links of any kind, so resolving `.` and `..` is all there is to
+// it. Unlike everywhere else, going above the root of a mount is an error
+// here, not the root itself.
+pub fn canonicalize(p: &Path) -> io::Result<PathBuf> {
+    let path = crate::sys::os::absolute(p)?;
+    let mut components = path.components();
+    let mut canonical = match components.next() {
+        Some(Component::Prefix(prefix)) => prefix.as_os_str().to_os_string(),
+        _ => return Err(io::Error::new(io::ErrorKind::InvalidInput, "path is not on a mount")),
+    };
+    canonical.push("/");
+
+    let mut parts = Vec::new();
+    for component in components {
+        match component {
+            Component::Normal(part) => parts.push(part),
+            Component::ParentDir => {
+                if parts.pop().is_none() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "path escapes the root of its mount",
+                    ));
+                }
+            }
+            Component::Prefix(_) | Component::RootDir | Component::CurDir => {}
+        }
+    }
+
+    let mut canonical = PathBuf::from(canonical);
+    canonical.extend(parts);
+    get_entry_type(&cstr(&canonical)?)?;
+    Ok(canonical)
 }
 
 pub fn copy(from: &Path, to: &Path) -> io::Result<u64> {
-    crate::io::copy(
-        &mut crate::fs::File::open(from)?,
-        &mut crate::fs::File::create(to)?,
-    )
+    let mut reader = crate::fs::File::open(from)?;
+    let len = reader.metadata()?.len();
+    let mut writer = crate::fs::File::create(to)?;
+    // Growing the file with every write is much slower than allocating all
+    // of it up front.
+    writer.set_len(len)?;
+
+    let copied = io::copy(&mut reader, &mut writer)?;
+    if copied != len {
+        writer.set_len(copied)?;
+    }
+    writer.sync_data()?;
+    Ok(copied)
+}
+
+// nn::account::Uid
+#[repr(C)]
+#[derive(Default)]
+struct Uid {
+    id: [u64; 2],
+}
+
+extern "C" {
+    // nn::account::Initialize()
+    #[link_name = "_ZN2nn7account10InitializeEv"]
+    fn AccountInitialize();
+    // nn::account::GetLastOpenedUser(nn::account::Uid*)
+    #[link_name = "_ZN2nn7account17GetLastOpenedUserEPNS0_3UidE"]
+    fn GetLastOpenedUser(user: *mut Uid);
+    // nn::fs::MountSaveData(char const*, nn::account::Uid const&)
+    #[link_name = "_ZN2nn2fs13MountSaveDataEPKcRKNS_7account3UidE"]
+    fn MountSaveData(name: *const c_char, user: *const Uid) -> u32;
+    // nn::fs::MountDeviceSaveData(char const*)
+    #[link_name = "_ZN2nn2fs19MountDeviceSaveDataEPKc"]
+    fn MountDeviceSaveData(name: *const c_char) -> u32;
+    // nn::fs::CommitSaveData(char const*)
+    #[link_name = "_ZN2nn2fs14CommitSaveDataEPKc"]
+    fn CommitSaveData(name: *const c_char) -> u32;
+    // nn::fs::MountSdCard(char const*)
+    #[link_name = "_ZN2nn2fs11MountSdCardEPKc"]
+    fn MountSdCard(name: *const c_char) -> u32;
+    // nn::fs::QueryMountRomCacheSize(unsigned long*)
+    #[link_name = "_ZN2nn2fs22QueryMountRomCacheSizeEPm"]
+    fn QueryMountRomCacheSize(size: *mut usize) -> u32;
+    // nn::fs::MountRom(char const*, void*, unsigned long)
+    #[link_name = "_ZN2nn2fs8MountRomEPKcPvm"]
+    fn MountRom(name: *const c_char, cache: *mut u8, cache_size: usize) -> u32;
+    // nn::fs::Unmount(char const*)
+    #[link_name = "_ZN2nn2fs7UnmountEPKc"]
+    fn Unmount(name: *const c_char);
+    // nn::fs::GetFreeSpaceSize(long*, char const*)
+    #[link_name = "_ZN2nn2fs16GetFreeSpaceSizeEPlPKc"]
+    fn GetFreeSpaceSize(size: *mut i64, path: *const c_char) -> u32;
+    // nn::fs::GetTotalSpaceSize(long*, char const*)
+    #[link_name = "_ZN2nn2fs17GetTotalSpaceSizeEPlPKc"]
+    fn GetTotalSpaceSize(size: *mut i64, path: *const c_char) -> u32;
+}
+
+// The name of the mount a path resolved by `cstr` is on.
+fn mount_name(path: &CStr) -> io::Result<CString> {
+    let path = path.to_bytes();
+    match path.iter().position(|&c| c == b':') {
+        Some(end) => Ok(CString::new(&path[..end])?),
+        None => Err(io::Error::new(io::ErrorKind::InvalidInput, "path is not on a mount")),
+    }
+}
+
+// Mounts made through std. nn::fs aborts when asked to unmount a name it
+// doesn't know, so these are the only ones that can be unmounted again.
+static MOUNTS_LOCK: Mutex = Mutex::new();
+static mut MOUNTS: Vec<Mount> = Vec::new();
+
+struct Mount {
+    name: CString,
+    // The cache of a RomFS mount, which has to outlive it.
+    _cache: Option<Box<[u8]>>,
+}
+
+fn mount(name: &CStr, cache: Option<Box<[u8]>>, f: impl FnOnce() -> u32) -> io::Result<()> {
+    unsafe {
+        let _guard = MOUNTS_LOCK.lock();
+        // nn::fs checks this as well, but only for the mounts it still knows
+        // about at the time.
+        if MOUNTS.iter().any(|mount| &*mount.name == name) {
+            return Err(io::Error::from_raw_os_error(result::FS_MOUNT_NAME_ALREADY_EXISTS));
+        }
+        r_try!(f())?;
+        MOUNTS.push(Mount { name: name.to_owned(), _cache: cache });
+    }
+    Ok(())
+}
+
+/// Mounts the save data of the user that last opened their account in the
+/// running title.
+pub fn mount_save_data(name: &CStr) -> io::Result<()> {
+    let mut user = Uid::default();
+    unsafe {
+        // Reference counted, so this doesn't get in the way of the title.
+        AccountInitialize();
+        GetLastOpenedUser(&mut user);
+    }
+    if user.id == [0, 0] {
+        return Err(io::Error::new(io::ErrorKind::NotFound, "no user account is open"));
+    }
+    mount(name, None, || unsafe { MountSaveData(name.as_ptr(), &user) })
+}
+
+pub fn mount_device_save_data(name: &CStr) -> io::Result<()> {
+    mount(name, None, || unsafe { MountDeviceSaveData(name.as_ptr()) })
+}
+
+pub fn mount_sd(name: &CStr) -> io::Result<()> {
+    mount(name, None, || unsafe { MountSdCard(name.as_ptr()) })
+}
+
+pub fn mount_rom(name: &CStr) -> io::Result<()> {
+    let mut size = 0;
+    unsafe { r_try!(QueryMountRomCacheSize(&mut size))? };
+    let mut cache = vec![0; size].into_boxed_slice();
+    let cache_ptr = cache.as_mut_ptr();
+    mount(name, Some(cache), || unsafe { MountRom(name.as_ptr(), cache_ptr, size) })
+}
+
+pub fn commit(name: &CStr) -> io::Result<()> {
+    unsafe { r_try!(CommitSaveData(name.as_ptr())) }
+}
+
+pub fn unmount(name: &CStr) -> io::Result<()> {
+    unsafe {
+        let _guard = MOUNTS_LOCK.lock();
+        let index = MOUNTS.iter().position(|mount| &*mount.name == name).ok_or_else(|| {
+            io::Error::new(io::ErrorKind::NotFound, "no such mount was made through std")
+        })?;
+        Unmount(name.as_ptr());
+        MOUNTS.swap_remove(index);
+    }
+    Ok(())
+}
+
+pub fn mounts() -> Vec<String> {
+    unsafe {
+        let _guard = MOUNTS_LOCK.lock();
+        MOUNTS.iter().map(|mount| mount.name.to_string_lossy().into_owned()).collect()
+    }
+}
+
+type SpaceFn = unsafe extern "C" fn(*mut i64, *const c_char) -> u32;
+
+fn space(path: &Path, f: SpaceFn) -> io::Result<u64> {
+    // Ask about the root of the mount, nn::fs doesn't look any further.
+    let mut root = mount_name(&cstr(path)?)?.into_bytes();
+    root.extend_from_slice(b":/");
+    let root = CString::new(root)?;
+
+    let mut size = 0;
+    unsafe { r_try!(f(&mut size, root.as_ptr()))? };
+    Ok(size as u64)
+}
+
+pub fn free_space(path: &Path) -> io::Result<u64> {
+    space(path, GetFreeSpaceSize)
+}
+
+pub fn total_space(path: &Path) -> io::Result<u64> {
+    space(path, GetTotalSpaceSize)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::fs::{self, File, OpenOptions};
+    use crate::io::{BufRead, BufReader, BufWriter, ErrorKind, Read, Seek, SeekFrom, Write};
+    use crate::path::{Path, PathBuf};
+    use crate::sync::atomic::{AtomicUsize, Ordering};
+    use crate::sync::Arc;
+
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn join(&self, path: &str) -> PathBuf {
+            self.0.join(path)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn tmpdir() -> TempDir {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let path = PathBuf::from(format!(
+            "sd:/rust-std-test-{}",
+            NEXT.fetch_add(1, Ordering::SeqCst)
+        ));
+        let _ = fs::remove_dir_all(&path);
+        fs::create_dir(&path).unwrap();
+        TempDir(path)
+    }
+
+    fn contents(path: &Path) -> Vec<u8> {
+        let mut buf = Vec::new();
+        File::open(path).unwrap().read_to_end(&mut buf).unwrap();
+        buf
+    }
+
+    fn write_file(path: &Path, data: &[u8]) {
+        File::create(path).unwrap().write_all(data).unwrap();
+    }
+
+    #[test]
+    fn access_mode_is_required() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"data");
+
+        let err = OpenOptions::new().open(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = OpenOptions::new().read(true).create(true).open(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = OpenOptions::new().read(true).truncate(true).open(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = OpenOptions::new().append(true).truncate(true).open(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn open_missing_file() {
+        let dir = tmpdir();
+        let path = dir.join("missing");
+
+        assert_eq!(File::open(&path).unwrap_err().kind(), ErrorKind::NotFound);
+        let err = OpenOptions::new().write(true).open(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn create() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+
+        OpenOptions::new().write(true).create(true).open(&path).unwrap();
+        assert_eq!(contents(&path), b"");
+
+        // An existing file is opened as is.
+        write_file(&path, b"data");
+        OpenOptions::new().write(true).create(true).open(&path).unwrap();
+        assert_eq!(contents(&path), b"data");
+    }
+
+    #[test]
+    fn create_new() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+
+        OpenOptions::new().write(true).create_new(true).open(&path).unwrap();
+        let err = OpenOptions::new().write(true).create_new(true).open(&path).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::AlreadyExists);
+    }
+
+    #[test]
+    fn truncate() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"some data");
+
+        OpenOptions::new().write(true).truncate(true).open(&path).unwrap();
+        assert_eq!(contents(&path), b"");
+        assert_eq!(fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[test]
+    fn write_overwrites_from_the_start() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"0123456789");
+
+        let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.write_all(b"abc").unwrap();
+        drop(f);
+        assert_eq!(contents(&path), b"abc3456789");
+    }
+
+    #[test]
+    fn write_past_eof_grows_the_file() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"0123");
+
+        let mut f = OpenOptions::new().write(true).open(&path).unwrap();
+        f.seek(SeekFrom::Start(2)).unwrap();
+        f.write_all(b"abcdef").unwrap();
+        assert_eq!(f.metadata().unwrap().len(), 8);
+        drop(f);
+        assert_eq!(contents(&path), b"01abcdef");
+    }
+
+    #[test]
+    fn write_after_seeking_past_eof() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+
+        let mut f = File::create(&path).unwrap();
+        assert_eq!(f.seek(SeekFrom::End(4)).unwrap(), 4);
+        f.write_all(b"x").unwrap();
+        drop(f);
+        assert_eq!(contents(&path), b"\0\0\0\0x");
+    }
+
+    #[test]
+    fn append_always_writes_at_the_end() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"01");
+
+        let mut f = OpenOptions::new().append(true).open(&path).unwrap();
+        f.write_all(b"23").unwrap();
+        f.seek(SeekFrom::Start(0)).unwrap();
+        f.write_all(b"45").unwrap();
+        drop(f);
+        assert_eq!(contents(&path), b"012345");
+
+        let mut f = OpenOptions::new().append(true).create(true).open(dir.join("new")).unwrap();
+        f.write_all(b"ab").unwrap();
+        drop(f);
+        assert_eq!(contents(&dir.join("new")), b"ab");
+    }
+
+    #[test]
+    fn read_and_write() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"hello world");
+
+        let mut f = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        let mut buf = [0; 5];
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        f.write_all(b"_").unwrap();
+        let mut rest = String::new();
+        f.read_to_string(&mut rest).unwrap();
+        assert_eq!(rest, "world");
+        drop(f);
+        assert_eq!(contents(&path), b"hello_world");
+    }
+
+    #[test]
+    fn read_at_eof() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"abc");
+
+        let mut f = File::open(&path).unwrap();
+        let mut buf = [0; 8];
+        f.seek(SeekFrom::End(0)).unwrap();
+        assert_eq!(f.read(&mut buf).unwrap(), 0);
+        f.seek(SeekFrom::Start(100)).unwrap();
+        assert_eq!(f.read(&mut buf).unwrap(), 0);
+    }
+
+    #[test]
+    fn seek() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"0123456789");
+
+        let mut f = File::open(&path).unwrap();
+        assert_eq!(f.seek(SeekFrom::End(-3)).unwrap(), 7);
+        let mut buf = [0; 3];
+        f.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"789");
+
+        assert_eq!(f.seek(SeekFrom::Current(-5)).unwrap(), 5);
+        assert_eq!(f.seek(SeekFrom::Current(2)).unwrap(), 7);
+        assert_eq!(f.seek(SeekFrom::Start(1)).unwrap(), 1);
+        assert_eq!(f.seek(SeekFrom::End(0)).unwrap(), 10);
+
+        let err = f.seek(SeekFrom::End(-11)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        let err = f.seek(SeekFrom::Current(-11)).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::InvalidInput);
+        // A failed seek leaves the position alone.
+        assert_eq!(f.seek(SeekFrom::Current(0)).unwrap(), 10);
+    }
+
+    #[test]
+    fn clones_share_cursor_and_size() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+
+        let mut a = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let mut b = a.try_clone().unwrap();
+        a.write_all(b"0123").unwrap();
+        assert_eq!(b.seek(SeekFrom::Current(0)).unwrap(), 4);
+        assert_eq!(b.metadata().unwrap().len(), 4);
+
+        b.write_all(b"45").unwrap();
+        assert_eq!(a.seek(SeekFrom::End(0)).unwrap(), 6);
+        assert_eq!(a.metadata().unwrap().len(), 6);
+    }
+
+    #[test]
+    fn clone_outlives_original() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"data");
+
+        let a = File::open(&path).unwrap();
+        let mut b = a.try_clone().unwrap();
+        drop(a);
+        let mut buf = String::new();
+        b.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "data");
+        drop(b);
+
+        // The handle was closed exactly once, so the file can be removed.
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn buffered_reader_and_writer_pair() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+
+        let file = OpenOptions::new().read(true).write(true).create(true).open(&path).unwrap();
+        let mut writer = BufWriter::new(file.try_clone().unwrap());
+        let mut reader = BufReader::new(file);
+
+        writer.write_all(b"first\nsecond\n").unwrap();
+        writer.flush().unwrap();
+        reader.seek(SeekFrom::Start(0)).unwrap();
+
+        let lines = reader.lines().map(|l| l.unwrap()).collect::<Vec<_>>();
+        assert_eq!(lines, ["first", "second"]);
+    }
+
+    #[test]
+    fn read_dir_in_batches() {
+        let dir = tmpdir();
+        let count = super::READ_DIR_BATCH * 3 + 1;
+        for i in 0..count {
+            write_file(&dir.join(&format!("file-{}", i)), b"abc");
+        }
+        fs::create_dir(dir.join("subdir")).unwrap();
+
+        let mut names = Vec::new();
+        for entry in fs::read_dir(&dir.0).unwrap() {
+            let entry = entry.unwrap();
+            let name = entry.file_name().into_string().unwrap();
+            assert_eq!(entry.path(), dir.join(&name));
+            if name == "subdir" {
+                assert!(entry.file_type().unwrap().is_dir());
+            } else {
+                assert!(entry.file_type().unwrap().is_file());
+                assert_eq!(entry.metadata().unwrap().len(), 3);
+            }
+            names.push(name);
+        }
+
+        names.sort();
+        let mut expected = (0..count).map(|i| format!("file-{}", i)).collect::<Vec<_>>();
+        expected.push("subdir".to_string());
+        expected.sort();
+        assert_eq!(names, expected);
+    }
+
+    #[test]
+    fn read_dir_empty_and_partial() {
+        let dir = tmpdir();
+        assert_eq!(fs::read_dir(&dir.0).unwrap().count(), 0);
+
+        for i in 0..super::READ_DIR_BATCH * 2 {
+            write_file(&dir.join(&format!("file-{}", i)), b"");
+        }
+        // Dropping a half-read listing closes the directory again.
+        let mut entries = fs::read_dir(&dir.0).unwrap();
+        entries.next().unwrap().unwrap();
+        drop(entries);
+        fs::remove_dir_all(&dir.0).unwrap();
+    }
+
+    #[test]
+    fn read_dir_missing() {
+        let dir = tmpdir();
+        let err = fs::read_dir(dir.join("missing")).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn positional_io_ignores_cursor() {
+        use crate::os::switch::fs::FileExt;
+
+        let dir = tmpdir();
+        let path = dir.join("file");
+        write_file(&path, b"0123456789");
+
+        let mut f = OpenOptions::new().read(true).write(true).open(&path).unwrap();
+        f.seek(SeekFrom::Start(3)).unwrap();
+
+        let mut buf = [0; 4];
+        f.read_exact_at(&mut buf, 5).unwrap();
+        assert_eq!(&buf, b"5678");
+        assert_eq!(f.read_at(&mut buf, 10).unwrap(), 0);
+        let err = f.read_exact_at(&mut buf, 8).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::UnexpectedEof);
+
+        f.write_all_at(b"ab", 0).unwrap();
+        f.write_all_at(b"xyz", 12).unwrap();
+        assert_eq!(f.metadata().unwrap().len(), 15);
+        assert_eq!(f.seek(SeekFrom::Current(0)).unwrap(), 3);
+        drop(f);
+        assert_eq!(contents(&path), b"ab23456789\0\0xyz");
+    }
+
+    #[test]
+    fn positional_reads_from_many_threads() {
+        use crate::os::switch::fs::FileExt;
+        use crate::thread;
+
+        let dir = tmpdir();
+        let path = dir.join("file");
+        let data = (0..4096u32).map(|i| i as u8).collect::<Vec<_>>();
+        write_file(&path, &data);
+
+        let file = Arc::new(File::open(&path).unwrap());
+        let threads = (0..4)
+            .map(|t| {
+                let file = file.clone();
+                let data = data.clone();
+                thread::spawn(move || {
+                    for chunk in (t..data.len() / 64).step_by(4) {
+                        let mut buf = [0; 64];
+                        file.read_exact_at(&mut buf, chunk as u64 * 64).unwrap();
+                        assert_eq!(&buf[..], &data[chunk * 64..][..64]);
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for t in threads {
+            t.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn commit_on_sync_keeps_the_file_usable() {
+        use crate::os::switch::fs::OpenOptionsExt;
+
+        let dir = tmpdir();
+        let path = dir.join("file");
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .commit_on_sync(true)
+            .open(&path)
+            .unwrap();
+        file.write_all(b"hello").unwrap();
+
+        // The SD card isn't save data, so there is nothing to commit, but the
+        // handle still has to come back.
+        assert!(file.sync_all().is_err());
+        file.write_all(b" world").unwrap();
+        file.seek(SeekFrom::Start(0)).unwrap();
+        let mut buf = String::new();
+        file.read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "hello world");
+    }
+
+    #[test]
+    fn commit_on_sync_failed_reopen_is_reported() {
+        use crate::os::switch::fs::OpenOptionsExt;
+        use crate::sys::result;
+        use crate::sys_common::AsInner;
+
+        let dir = tmpdir();
+        let path = dir.join("file");
+        let file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create(true)
+            .commit_on_sync(true)
+            .open(&path)
+            .unwrap();
+
+        // Do what a commit does, but take the file away while its handle is
+        // closed so it can't be reopened.
+        let inner = file.as_inner();
+        let commit = inner.0.commit.as_ref().unwrap();
+        unsafe {
+            commit.lock.write();
+            nnsdk::fs::CloseFile(inner.handle().unwrap());
+            (*inner.0.handle.get()).handle = 0 as _;
+            fs::remove_file(&path).unwrap();
+            let err = inner.reopen(commit).unwrap_err();
+            commit.lock.write_unlock();
+            assert_eq!(err.raw_os_error(), Some(result::FS_PATH_NOT_FOUND));
+        }
+
+        // The file stays broken, with the error from the reopen rather than
+        // one about the missing handle.
+        let mut buf = [0; 4];
+        let errors = [
+            (&file).read(&mut buf).unwrap_err(),
+            (&file).write(b"data").unwrap_err(),
+            file.sync_all().unwrap_err(),
+            file.set_len(0).unwrap_err(),
+        ];
+        for err in errors.iter() {
+            assert_eq!(err.raw_os_error(), Some(result::FS_PATH_NOT_FOUND));
+        }
+    }
+
+    #[test]
+    fn unmount_unknown() {
+        use crate::os::switch::fs::unmount;
+
+        assert_eq!(unmount("rust-std-test").unwrap_err().kind(), ErrorKind::NotFound);
+        assert_eq!(unmount("sd\0").unwrap_err().kind(), ErrorKind::InvalidInput);
+    }
+
+    #[test]
+    fn mount_names_are_unique() {
+        use crate::os::switch::fs::{mount_sd, mounts, unmount};
+
+        mount_sd("rust-std-sd").unwrap();
+        assert!(mounts().iter().any(|name| name == "rust-std-sd"));
+        assert_eq!(mount_sd("rust-std-sd").unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert!(fs::metadata("rust-std-sd:/").unwrap().is_dir());
+
+        unmount("rust-std-sd").unwrap();
+        assert!(!mounts().iter().any(|name| name == "rust-std-sd"));
+    }
+
+    #[test]
+    fn space() {
+        use crate::os::switch::fs::{free_space, total_space};
+
+        let dir = tmpdir();
+        let free = free_space(&dir.0).unwrap();
+        let total = total_space(&dir.0).unwrap();
+        assert!(0 < free && free <= total);
+        assert_eq!(total_space("sd:/").unwrap(), total);
+        assert!(free_space("rust-std-missing:/").is_err());
+    }
+
+    #[test]
+    fn canonicalize() {
+        let dir = tmpdir();
+        fs::create_dir(dir.join("a")).unwrap();
+        write_file(&dir.join("file"), b"");
+
+        let cases = [
+            (dir.join("./a/../file"), dir.join("file")),
+            (dir.join("a/./.."), dir.0.clone()),
+            (dir.join("a/"), dir.join("a")),
+            // relative to the current directory, `sd:/` by default
+            (dir.0.strip_prefix("sd:/").unwrap().join("a"), dir.join("a")),
+            (PathBuf::from("/").join(dir.0.strip_prefix("sd:/").unwrap()), dir.0.clone()),
+            (PathBuf::from("sd:"), PathBuf::from("sd:/")),
+        ];
+        for (path, canonical) in cases.iter() {
+            assert_eq!(fs::canonicalize(path).unwrap(), *canonical, "{}", path.display());
+        }
+
+        let escape = fs::canonicalize("sd:/a/../..").unwrap_err();
+        assert_eq!(escape.kind(), ErrorKind::InvalidInput);
+        let missing = fs::canonicalize(dir.join("a/../missing")).unwrap_err();
+        assert_eq!(missing.kind(), ErrorKind::NotFound);
+    }
+
+    #[test]
+    fn remove_dir_all_fallback() {
+        let dir = tmpdir();
+        let root = dir.join("root");
+        fs::create_dir_all(root.join("a/b/c")).unwrap();
+        fs::create_dir(root.join("empty")).unwrap();
+        for i in 0..40 {
+            write_file(&root.join(format!("a/{}", i)), b"data");
+        }
+        write_file(&root.join("a/b/c/file"), b"data");
+
+        super::remove_dir_all_recursive(&root).unwrap();
+        assert_eq!(fs::metadata(&root).unwrap_err().kind(), ErrorKind::NotFound);
+        assert!(fs::metadata(&dir.0).unwrap().is_dir());
+    }
+
+    #[test]
+    fn unflushed_writes_are_kept() {
+        let dir = tmpdir();
+        let path = dir.join("file");
+        let mut file = File::create(&path).unwrap();
+        file.write_all(b"hello").unwrap();
+        drop(file);
+        assert_eq!(contents(&path), b"hello");
+    }
+
+    #[test]
+    fn copy() {
+        let dir = tmpdir();
+        let data = (0..100_000u32).map(|i| i as u8).collect::<Vec<_>>();
+        write_file(&dir.join("from"), &data);
+        write_file(&dir.join("to"), &[1; 200_000]);
+
+        assert_eq!(fs::copy(dir.join("from"), dir.join("to")).unwrap(), data.len() as u64);
+        assert_eq!(contents(&dir.join("to")), data);
+        assert!(fs::copy(dir.join("missing"), dir.join("to")).is_err());
+    }
+
+    // Writes a log line by line. The `_flushing` variant flushes after every
+    // line, which is what every single write used to do.
+    fn write_lines(b: &mut test::Bencher, flush: bool) {
+        let dir = tmpdir();
+        let line = [b'x'; 63];
+        b.bytes = 64 * 64;
+        b.iter(|| {
+            let mut file = File::create(dir.join("log")).unwrap();
+            for _ in 0..64 {
+                file.write_all(&line).unwrap();
+                file.write_all(b"\n").unwrap();
+                if flush {
+                    file.flush().unwrap();
+                }
+            }
+        });
+    }
+
+    #[bench]
+    fn bench_write_lines(b: &mut test::Bencher) {
+        write_lines(b, false);
+    }
+
+    #[bench]
+    fn bench_write_lines_flushing(b: &mut test::Bencher) {
+        write_lines(b, true);
+    }
+
+    #[bench]
+    fn bench_copy(b: &mut test::Bencher) {
+        let dir = tmpdir();
+        write_file(&dir.join("from"), &[0; 1 << 20]);
+        b.bytes = 1 << 20;
+        b.iter(|| fs::copy(dir.join("from"), dir.join("to")).unwrap());
+    }
 }
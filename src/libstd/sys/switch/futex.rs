@@ -0,0 +1,87 @@
+//! Futex-like waiting on top of the kernel's address arbiter.
+//!
+//! Horizon lets a thread sleep on an arbitrary 32-bit word in its address
+//! space (`svcWaitForAddress`) and be woken up by another thread signalling
+//! that same address (`svcSignalToAddress`). That maps directly onto the
+//! futex operations the atomics-based primitives are written against, without
+//! having to create any kernel objects up front.
+
+use crate::cmp;
+use crate::sync::atomic::AtomicU32;
+use crate::time::Duration;
+
+// ArbitrationType::WaitIfEqual
+const ARBITRATION_WAIT_IF_EQUAL: u64 = 2;
+// SignalType::Signal
+const SIGNAL_TYPE_SIGNAL: u64 = 0;
+
+// Kernel result codes (module 1).
+const RESULT_TIMED_OUT: u32 = 0xEA01;
+const RESULT_INVALID_STATE: u32 = 0xFA01;
+
+unsafe fn svc_wait_for_address(address: usize, arb_type: u64, value: i32, timeout: i64) -> u32 {
+    let mut x0 = address as u64;
+    let mut x1 = arb_type;
+    let mut x2 = value as u64;
+    let mut x3 = timeout as u64;
+    llvm_asm!("svc 0x34"
+        : "+{x0}"(x0), "+{x1}"(x1), "+{x2}"(x2), "+{x3}"(x3)
+        :
+        : "memory"
+        : "volatile");
+    x0 as u32
+}
+
+unsafe fn svc_signal_to_address(address: usize, signal_type: u64, value: i32, count: i32) -> u32 {
+    let mut x0 = address as u64;
+    let mut x1 = signal_type;
+    let mut x2 = value as u64;
+    let mut x3 = count as u64;
+    llvm_asm!("svc 0x35"
+        : "+{x0}"(x0), "+{x1}"(x1), "+{x2}"(x2), "+{x3}"(x3)
+        :
+        : "memory"
+        : "volatile");
+    x0 as u32
+}
+
+/// Blocks the current thread as long as `futex` holds `expected`, until it
+/// is woken up by `futex_wake` or `timeout` elapses.
+///
+/// Returns `false` only if the wait timed out. As with any futex, spurious
+/// wakeups are possible and callers are expected to re-check their condition.
+pub fn futex_wait(futex: &AtomicU32, expected: u32, timeout: Option<Duration>) -> bool {
+    // A negative timeout waits forever.
+    let timeout = match timeout {
+        Some(dur) => cmp::min(dur.as_nanos(), i64::max_value() as u128) as i64,
+        None => -1,
+    };
+    let address = futex as *const AtomicU32 as usize;
+
+    let rc = unsafe {
+        svc_wait_for_address(address, ARBITRATION_WAIT_IF_EQUAL, expected as i32, timeout)
+    };
+
+    // `RESULT_INVALID_STATE` means the value had already changed, which is
+    // just an early wakeup as far as we are concerned.
+    debug_assert!(rc == 0 || rc == RESULT_TIMED_OUT || rc == RESULT_INVALID_STATE);
+    rc != RESULT_TIMED_OUT
+}
+
+/// Wakes up to `count` threads waiting on `futex`.
+pub fn futex_wake(futex: &AtomicU32, count: u32) {
+    let address = futex as *const AtomicU32 as usize;
+    let count = cmp::min(count, i32::max_value() as u32) as i32;
+
+    let rc = unsafe { svc_signal_to_address(address, SIGNAL_TYPE_SIGNAL, 0, count) };
+    debug_assert_eq!(rc, 0);
+}
+
+/// Wakes every thread waiting on `futex`.
+pub fn futex_wake_all(futex: &AtomicU32) {
+    let address = futex as *const AtomicU32 as usize;
+
+    // A negative count signals all waiters.
+    let rc = unsafe { svc_signal_to_address(address, SIGNAL_TYPE_SIGNAL, 0, -1) };
+    debug_assert_eq!(rc, 0);
+}
@@ -43,10 +43,14 @@ IS_NRO:
 .space 0xD0
 ");
 
+#[macro_use]
+pub mod weak;
+
 pub mod alloc;
 pub mod args;
 pub mod cmath;
 pub mod env;
+pub mod ext;
 pub mod fast_thread_local;
 pub mod fs;
 pub mod io;
@@ -56,6 +60,7 @@ pub mod os;
 pub mod path;
 pub mod pipe;
 pub mod process;
+pub mod result;
 pub mod stack_overflow;
 pub mod stdio;
 pub mod thread;
@@ -68,12 +73,14 @@ pub use crate::sys_common::os_str_bytes as os_str;
 
 cfg_if::cfg_if! {
     if #[cfg(target_feature = "atomics")] {
+        pub mod futex;
         #[path = "condvar_atomics.rs"]
         pub mod condvar;
         #[path = "mutex_atomics.rs"]
         pub mod mutex;
         #[path = "rwlock_atomics.rs"]
         pub mod rwlock;
+        pub mod thread_parker;
     } else {
         pub mod condvar;
         pub mod mutex;
@@ -84,6 +91,25 @@ cfg_if::cfg_if! {
 #[cfg(not(test))]
 pub fn init() {}
 
+// The target links us with `-init=__custom_init`, so nn::ro calls this as the
+// module's `DT_INIT` once it's loaded, before any other code of ours runs.
+#[cfg(not(test))]
+#[no_mangle]
+pub unsafe extern "C" fn __custom_init() {
+    fast_thread_local::init();
+}
+
+// Plugins are never really exited, so this is where std gets torn down: the
+// target links us with `-fini=__custom_fini`, which nn::ro calls as the
+// module's `DT_FINI` when it is unloaded. Plugins that stay loaded until the
+// title exits never get here, and the title's exit cleans up after them.
+#[cfg(not(test))]
+#[no_mangle]
+pub unsafe extern "C" fn __custom_fini() {
+    let _ = net::shutdown();
+    crate::sys_common::cleanup();
+}
+
 pub fn unsupported<T>() -> crate::io::Result<T> {
     Err(unsupported_err())
 }
@@ -92,8 +118,28 @@ pub fn unsupported_err() -> crate::io::Error {
     crate::io::Error::new(crate::io::ErrorKind::Other, "operation not supported on wasm yet")
 }
 
-pub fn decode_error_kind(_code: i32) -> crate::io::ErrorKind {
-    crate::io::ErrorKind::Other
+pub fn decode_error_kind(code: i32) -> ErrorKind {
+    if result::is_result(code) {
+        return result::kind(code);
+    }
+
+    match code as libc::c_int {
+        libc::ECONNREFUSED => ErrorKind::ConnectionRefused,
+        libc::ECONNRESET => ErrorKind::ConnectionReset,
+        libc::EPERM | libc::EACCES => ErrorKind::PermissionDenied,
+        libc::EPIPE => ErrorKind::BrokenPipe,
+        libc::ENOTCONN => ErrorKind::NotConnected,
+        libc::ECONNABORTED => ErrorKind::ConnectionAborted,
+        libc::EADDRNOTAVAIL => ErrorKind::AddrNotAvailable,
+        libc::EADDRINUSE => ErrorKind::AddrInUse,
+        libc::ENOENT => ErrorKind::NotFound,
+        libc::EINTR => ErrorKind::Interrupted,
+        libc::EINVAL => ErrorKind::InvalidInput,
+        libc::ETIMEDOUT => ErrorKind::TimedOut,
+        libc::EEXIST => ErrorKind::AlreadyExists,
+        x if x == libc::EAGAIN || x == libc::EWOULDBLOCK => ErrorKind::WouldBlock,
+        _ => ErrorKind::Other,
+    }
 }
 
 // This enum is used as the storage for a bunch of types which can't actually
@@ -1,14 +1,62 @@
 use crate::cell::UnsafeCell;
 use crate::mem::MaybeUninit;
+use crate::sync::atomic::{AtomicUsize, Ordering};
+use crate::sys::thread::Thread;
+
+use nnsdk::os::MutexType;
+
+// The nn::os synchronization objects need to be initialized in place before
+// use, but our constructors are `const` and the value may still be moved
+// around before it is first used. Objects are therefore initialized lazily on
+// first use, which is guaranteed to happen at their final address. `state`
+// tracks the progress of that initialization so only one thread ever runs it.
+pub const UNINITIALIZED: usize = 0;
+const INITIALIZING: usize = 1;
+const INITIALIZED: usize = 2;
+
+#[inline]
+pub unsafe fn lazy_init(state: &AtomicUsize, init: impl FnOnce()) {
+    if state.load(Ordering::Acquire) == INITIALIZED {
+        return;
+    }
+    lazy_init_slow(state, init)
+}
+
+#[cold]
+unsafe fn lazy_init_slow(state: &AtomicUsize, init: impl FnOnce()) {
+    loop {
+        match state.compare_exchange(
+            UNINITIALIZED,
+            INITIALIZING,
+            Ordering::Acquire,
+            Ordering::Acquire,
+        ) {
+            Ok(_) => {
+                init();
+                state.store(INITIALIZED, Ordering::Release);
+                return;
+            }
+            Err(INITIALIZED) => return,
+            // Someone else is running the initializer, which is short and
+            // can't block, so just wait for it to finish.
+            Err(_) => Thread::yield_now(),
+        }
+    }
+}
+
+#[inline]
+pub fn is_initialized(state: &AtomicUsize) -> bool {
+    state.load(Ordering::Acquire) == INITIALIZED
+}
 
 pub struct Mutex {
-    inner: UnsafeCell<libc::pthread_mutex_t>,
+    inner: UnsafeCell<MaybeUninit<MutexType>>,
+    state: AtomicUsize,
 }
 
 #[inline]
-#[allow(dead_code)]
-pub unsafe fn raw(m: &Mutex) -> *mut libc::pthread_mutex_t {
-    m.inner.get()
+pub unsafe fn raw(m: &Mutex) -> *mut MutexType {
+    m.get()
 }
 
 unsafe impl Send for Mutex {}
@@ -17,76 +65,47 @@ unsafe impl Sync for Mutex {}
 #[allow(dead_code)] // sys isn't exported yet
 impl Mutex {
     pub const fn new() -> Mutex {
-        // Might be moved to a different address, so it is better to avoid
-        // initialization of potentially opaque OS data before it landed.
-        // Be very careful using this newly constructed `Mutex`, reentrant
-        // locking is undefined behavior until `init` is called!
-        Mutex { inner: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER) }
+        Mutex {
+            inner: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicUsize::new(UNINITIALIZED),
+        }
     }
     #[inline]
     pub unsafe fn init(&mut self) {
-        // Issue #33770
-        //
-        // A pthread mutex initialized with PTHREAD_MUTEX_INITIALIZER will have
-        // a type of PTHREAD_MUTEX_DEFAULT, which has undefined behavior if you
-        // try to re-lock it from the same thread when you already hold a lock.
-        //
-        // In practice, glibc takes advantage of this undefined behavior to
-        // implement hardware lock elision, which uses hardware transactional
-        // memory to avoid acquiring the lock. While a transaction is in
-        // progress, the lock appears to be unlocked. This isn't a problem for
-        // other threads since the transactional memory will abort if a conflict
-        // is detected, however no abort is generated if re-locking from the
-        // same thread.
-        //
-        // Since locking the same mutex twice will result in two aliasing &mut
-        // references, we instead create the mutex with type
-        // PTHREAD_MUTEX_NORMAL which is guaranteed to deadlock if we try to
-        // re-lock it from the same thread, thus avoiding undefined behavior.
-        let mut attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
-        let r = libc::pthread_mutexattr_init(attr.as_mut_ptr());
-        debug_assert_eq!(r, 0);
-        let r = libc::pthread_mutexattr_settype(attr.as_mut_ptr(), libc::PTHREAD_MUTEX_NORMAL);
-        debug_assert_eq!(r, 0);
-        let r = libc::pthread_mutex_init(self.inner.get(), attr.as_ptr());
-        debug_assert_eq!(r, 0);
-        let r = libc::pthread_mutexattr_destroy(attr.as_mut_ptr());
-        debug_assert_eq!(r, 0);
+        // Initialization happens on first use instead, see `lazy_init`.
     }
     #[inline]
     pub unsafe fn lock(&self) {
-        let r = libc::pthread_mutex_lock(self.inner.get());
-        debug_assert_eq!(r, 0);
+        nnsdk::os::LockMutex(self.get());
     }
     #[inline]
     pub unsafe fn unlock(&self) {
-        let r = libc::pthread_mutex_unlock(self.inner.get());
-        debug_assert_eq!(r, 0);
+        nnsdk::os::UnlockMutex(self.get());
     }
     #[inline]
     pub unsafe fn try_lock(&self) -> bool {
-        libc::pthread_mutex_trylock(self.inner.get()) == 0
+        nnsdk::os::TryLockMutex(self.get())
     }
     #[inline]
-    #[cfg(not(target_os = "dragonfly"))]
     pub unsafe fn destroy(&self) {
-        let r = libc::pthread_mutex_destroy(self.inner.get());
-        debug_assert_eq!(r, 0);
+        if is_initialized(&self.state) {
+            nnsdk::os::FinalizeMutex(self.inner.get() as *mut MutexType);
+        }
     }
     #[inline]
-    #[cfg(target_os = "dragonfly")]
-    pub unsafe fn destroy(&self) {
-        let r = libc::pthread_mutex_destroy(self.inner.get());
-        // On DragonFly pthread_mutex_destroy() returns EINVAL if called on a
-        // mutex that was just initialized with libc::PTHREAD_MUTEX_INITIALIZER.
-        // Once it is used (locked/unlocked) or pthread_mutex_init() is called,
-        // this behaviour no longer occurs.
-        debug_assert!(r == 0 || r == libc::EINVAL);
+    unsafe fn get(&self) -> *mut MutexType {
+        let inner = self.inner.get() as *mut MutexType;
+        // A non-recursive mutex aborts when locked twice from the same thread,
+        // which satisfies std's requirement of never handing out two aliasing
+        // guards.
+        lazy_init(&self.state, || nnsdk::os::InitializeMutex(inner, false, 0));
+        inner
     }
 }
 
 pub struct ReentrantMutex {
-    inner: UnsafeCell<libc::pthread_mutex_t>,
+    inner: UnsafeCell<MaybeUninit<MutexType>>,
+    state: AtomicUsize,
 }
 
 unsafe impl Send for ReentrantMutex {}
@@ -94,39 +113,39 @@ unsafe impl Sync for ReentrantMutex {}
 
 impl ReentrantMutex {
     pub const unsafe fn uninitialized() -> ReentrantMutex {
-        ReentrantMutex { inner: UnsafeCell::new(libc::PTHREAD_MUTEX_INITIALIZER) }
+        ReentrantMutex {
+            inner: UnsafeCell::new(MaybeUninit::uninit()),
+            state: AtomicUsize::new(UNINITIALIZED),
+        }
     }
 
     pub unsafe fn init(&self) {
-        let mut attr = MaybeUninit::<libc::pthread_mutexattr_t>::uninit();
-        let result = libc::pthread_mutexattr_init(attr.as_mut_ptr());
-        debug_assert_eq!(result, 0);
-        let result =
-            libc::pthread_mutexattr_settype(attr.as_mut_ptr(), libc::PTHREAD_MUTEX_RECURSIVE);
-        debug_assert_eq!(result, 0);
-        let result = libc::pthread_mutex_init(self.inner.get(), attr.as_ptr());
-        debug_assert_eq!(result, 0);
-        let result = libc::pthread_mutexattr_destroy(attr.as_mut_ptr());
-        debug_assert_eq!(result, 0);
+        let inner = self.get();
+        lazy_init(&self.state, || nnsdk::os::InitializeMutex(inner, true, 0));
     }
 
     pub unsafe fn lock(&self) {
-        let result = libc::pthread_mutex_lock(self.inner.get());
-        debug_assert_eq!(result, 0);
+        nnsdk::os::LockMutex(self.get());
     }
 
     #[inline]
     pub unsafe fn try_lock(&self) -> bool {
-        libc::pthread_mutex_trylock(self.inner.get()) == 0
+        nnsdk::os::TryLockMutex(self.get())
     }
 
     pub unsafe fn unlock(&self) {
-        let result = libc::pthread_mutex_unlock(self.inner.get());
-        debug_assert_eq!(result, 0);
+        nnsdk::os::UnlockMutex(self.get());
     }
 
     pub unsafe fn destroy(&self) {
-        let result = libc::pthread_mutex_destroy(self.inner.get());
-        debug_assert_eq!(result, 0);
+        // A mutex that was never initialized must not be finalized either.
+        if is_initialized(&self.state) {
+            nnsdk::os::FinalizeMutex(self.get());
+        }
+    }
+
+    #[inline]
+    fn get(&self) -> *mut MutexType {
+        self.inner.get() as *mut MutexType
     }
 }
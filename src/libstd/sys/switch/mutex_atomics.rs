@@ -1,22 +1,27 @@
-use crate::arch::wasm32;
 use crate::cell::UnsafeCell;
-use crate::mem;
-use crate::sync::atomic::{AtomicU32, AtomicUsize, Ordering::SeqCst};
+use crate::sync::atomic::{AtomicU32, AtomicUsize, Ordering::*};
+use crate::sys::futex::{futex_wait, futex_wake};
 use crate::sys::thread;
 
 pub struct Mutex {
-    locked: AtomicUsize,
+    futex: AtomicU32,
 }
 
-// Mutexes have a pretty simple implementation where they contain an `i32`
-// internally that is 0 when unlocked and 1 when the mutex is locked.
-// Acquisition has a fast path where it attempts to cmpxchg the 0 to a 1, and
-// if it fails it then waits for a notification. Releasing a lock is then done
-// by swapping in 0 and then notifying any waiters, if present.
+const UNLOCKED: u32 = 0;
+const LOCKED: u32 = 1;
+const CONTENDED: u32 = 2;
+
+// Mutexes are a single word which is 0 when unlocked, 1 when locked without
+// any waiters and 2 when locked with (possibly) some threads waiting on it.
+// Acquisition has a fast path where it attempts to cmpxchg the 0 to a 1, so an
+// uncontended lock never enters the kernel. Otherwise the word is marked as
+// contended and we sleep on it through the address arbiter. Releasing the lock
+// swaps in 0 and only signals the address if the previous value says that
+// someone might be waiting.
 
 impl Mutex {
     pub const fn new() -> Mutex {
-        Mutex { locked: AtomicUsize::new(0) }
+        Mutex { futex: AtomicU32::new(UNLOCKED) }
     }
 
     #[inline]
@@ -24,64 +29,66 @@ impl Mutex {
         // nothing to do
     }
 
+    #[inline]
     pub unsafe fn lock(&self) {
-        while !self.try_lock() {
-            let val = wasm32::i32_atomic_wait(
-                self.ptr(),
-                1,  // we expect our mutex is locked
-                -1, // wait infinitely
-            );
-            // we should have either woke up (0) or got a not-equal due to a
-            // race (1). We should never time out (2)
-            debug_assert!(val == 0 || val == 1);
+        if !self.try_lock() {
+            self.lock_contended();
+        }
+    }
+
+    #[cold]
+    fn lock_contended(&self) {
+        // Whoever acquires the lock through this path can't know whether
+        // other threads are still sleeping, so it has to conservatively leave
+        // the state as `CONTENDED`.
+        while self.futex.swap(CONTENDED, Acquire) != UNLOCKED {
+            futex_wait(&self.futex, CONTENDED, None);
         }
     }
 
+    #[inline]
     pub unsafe fn unlock(&self) {
-        let prev = self.locked.swap(0, SeqCst);
-        debug_assert_eq!(prev, 1);
-        wasm32::atomic_notify(self.ptr(), 1); // wake up one waiter, if any
+        if self.futex.swap(UNLOCKED, Release) == CONTENDED {
+            futex_wake(&self.futex, 1); // wake up one waiter, if any
+        }
     }
 
     #[inline]
     pub unsafe fn try_lock(&self) -> bool {
-        self.locked.compare_exchange(0, 1, SeqCst, SeqCst).is_ok()
+        self.futex.compare_exchange(UNLOCKED, LOCKED, Acquire, Relaxed).is_ok()
     }
 
     #[inline]
     pub unsafe fn destroy(&self) {
         // nothing to do
     }
-
-    #[inline]
-    fn ptr(&self) -> *mut i32 {
-        assert_eq!(mem::size_of::<usize>(), mem::size_of::<i32>());
-        self.locked.as_mut_ptr() as *mut i32
-    }
 }
 
 pub struct ReentrantMutex {
-    owner: AtomicU32,
+    mutex: Mutex,
+    owner: AtomicUsize,
     recursions: UnsafeCell<u32>,
 }
 
 unsafe impl Send for ReentrantMutex {}
 unsafe impl Sync for ReentrantMutex {}
 
-// Reentrant mutexes are similarly implemented to mutexs above except that
-// instead of "1" meaning unlocked we use the id of a thread to represent
-// whether it has locked a mutex. That way we have an atomic counter which
-// always holds the id of the thread that currently holds the lock (or 0 if the
-// lock is unlocked).
+// Reentrant mutexes wrap the mutex above and additionally remember which
+// thread currently holds it (or 0 if the lock is unlocked). A thread that
+// finds its own id in `owner` already holds the inner mutex and only bumps the
+// `recursions` counter, which is fine to do non-atomically as we hold the
+// lock. The inner mutex is released once that counter drops back to zero.
 //
-// Once a thread acquires a lock recursively, which it detects by looking at
-// the value that's already there, it will update a local `recursions` counter
-// in a nonatomic fashion (as we hold the lock). The lock is then fully
-// released when this recursion counter reaches 0.
+// `owner` is only ever set to the current thread's id by the current thread,
+// so a relaxed load is enough to tell whether we are the owner.
 
 impl ReentrantMutex {
     pub const unsafe fn uninitialized() -> ReentrantMutex {
-        ReentrantMutex { owner: AtomicU32::new(0), recursions: UnsafeCell::new(0) }
+        ReentrantMutex {
+            mutex: Mutex::new(),
+            owner: AtomicUsize::new(0),
+            recursions: UnsafeCell::new(0),
+        }
     }
 
     pub unsafe fn init(&self) {
@@ -90,58 +97,47 @@ impl ReentrantMutex {
 
     pub unsafe fn lock(&self) {
         let me = thread::my_id();
-        while let Err(owner) = self._try_lock(me) {
-            let val = wasm32::i32_atomic_wait(self.ptr(), owner as i32, -1);
-            debug_assert!(val == 0 || val == 1);
+        if self.owner.load(Relaxed) == me {
+            self.increment_recursions();
+        } else {
+            self.mutex.lock();
+            self.owner.store(me, Relaxed);
+            debug_assert_eq!(*self.recursions.get(), 0);
+            *self.recursions.get() = 1;
         }
     }
 
     #[inline]
     pub unsafe fn try_lock(&self) -> bool {
-        self._try_lock(thread::my_id()).is_ok()
+        let me = thread::my_id();
+        if self.owner.load(Relaxed) == me {
+            self.increment_recursions();
+            true
+        } else if self.mutex.try_lock() {
+            self.owner.store(me, Relaxed);
+            debug_assert_eq!(*self.recursions.get(), 0);
+            *self.recursions.get() = 1;
+            true
+        } else {
+            false
+        }
     }
 
-    #[inline]
-    unsafe fn _try_lock(&self, id: u32) -> Result<(), u32> {
-        let id = id.checked_add(1).unwrap(); // make sure `id` isn't 0
-        match self.owner.compare_exchange(0, id, SeqCst, SeqCst) {
-            // we transitioned from unlocked to locked
-            Ok(_) => {
-                debug_assert_eq!(*self.recursions.get(), 0);
-                Ok(())
-            }
-
-            // we currently own this lock, so let's update our count and return
-            // true.
-            Err(n) if n == id => {
-                *self.recursions.get() += 1;
-                Ok(())
-            }
-
-            // Someone else owns the lock, let our caller take care of it
-            Err(other) => Err(other),
-        }
+    unsafe fn increment_recursions(&self) {
+        *self.recursions.get() = (*self.recursions.get())
+            .checked_add(1)
+            .expect("lock count overflow in reentrant mutex");
     }
 
     pub unsafe fn unlock(&self) {
-        // If we didn't ever recursively lock the lock then we fully unlock the
-        // mutex and wake up a waiter, if any. Otherwise we decrement our
-        // recursive counter and let some one else take care of the zero.
-        match *self.recursions.get() {
-            0 => {
-                self.owner.swap(0, SeqCst);
-                wasm32::atomic_notify(self.ptr() as *mut i32, 1); // wake up one waiter, if any
-            }
-            ref mut n => *n -= 1,
+        *self.recursions.get() -= 1;
+        if *self.recursions.get() == 0 {
+            self.owner.store(0, Relaxed);
+            self.mutex.unlock();
         }
     }
 
     pub unsafe fn destroy(&self) {
         // nothing to do...
     }
-
-    #[inline]
-    fn ptr(&self) -> *mut i32 {
-        self.owner.as_mut_ptr() as *mut i32
-    }
 }
@@ -1,10 +1,15 @@
+use crate::alloc::{alloc, dealloc, Layout};
 use crate::cmp;
 use crate::ffi::CStr;
 use crate::io::{self, IoSlice, IoSliceMut};
 use crate::mem;
 use crate::net::{Shutdown, SocketAddr};
 use crate::str;
+use crate::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use crate::sys::ext::net::SocketConfig;
 use crate::sys::fd::FileDesc;
+use crate::sys::result;
+use crate::sys_common::mutex::Mutex;
 use crate::sys_common::net::{getsockopt, setsockopt, sockaddr_to_addr};
 use crate::sys_common::{AsInner, FromInner, IntoInner};
 use crate::time::{Duration, Instant};
@@ -20,7 +25,124 @@ pub type wrlen_t = size_t;
 
 pub struct Socket(FileDesc);
 
-pub fn init() {}
+extern "C" {
+    // nn::socket::Initialize(void*, unsigned long, unsigned long, int)
+    #[link_name = "_ZN2nn6socket10InitializeEPvmmi"]
+    fn Initialize(
+        pool: *mut u8,
+        pool_size: usize,
+        allocator_size: usize,
+        concurrency: c_int,
+    ) -> u32;
+    // nn::socket::Finalize()
+    #[link_name = "_ZN2nn6socket8FinalizeEv"]
+    fn Finalize() -> u32;
+}
+
+// The pool has to be page aligned. nn::socket keeps the allocator it uses for
+// its own bookkeeping in the first part of it.
+const POOL_ALIGN: usize = 0x1000;
+const ALLOCATOR_SIZE: usize = 0x2_0000;
+
+enum State {
+    Uninitialized,
+    Owned(*mut u8, Layout),
+    // Someone else, most likely the title, initialized nn::socket before us.
+    Borrowed,
+}
+
+static INITIALIZED: AtomicBool = AtomicBool::new(false);
+static STATE_LOCK: Mutex = Mutex::new();
+static mut STATE: State = State::Uninitialized;
+// Buffer sizes for new TCP sockets, only set by `configure` and zero
+// otherwise. They are stored before `INITIALIZED` is set, and every socket is
+// created after `init` observed that, so reading them needs no lock.
+static TCP_TX_BUF: AtomicUsize = AtomicUsize::new(0);
+static TCP_RX_BUF: AtomicUsize = AtomicUsize::new(0);
+
+/// Initializes the socket service with the default configuration, unless that
+/// already happened. Called before every operation that creates a socket or
+/// resolves a name.
+pub fn init() {
+    if INITIALIZED.load(Ordering::Acquire) {
+        return;
+    }
+    unsafe {
+        let _guard = STATE_LOCK.lock();
+        if let State::Uninitialized = STATE {
+            // A failure shows up as an error from the socket call that
+            // follows.
+            let _ = start(&SocketConfig::default());
+        }
+    }
+}
+
+pub fn configure(config: &SocketConfig) -> io::Result<()> {
+    unsafe {
+        let _guard = STATE_LOCK.lock();
+        if let State::Uninitialized = STATE {
+            TCP_TX_BUF.store(config.tcp_tx_buf, Ordering::Relaxed);
+            TCP_RX_BUF.store(config.tcp_rx_buf, Ordering::Relaxed);
+            start(config).map_err(|e| {
+                TCP_TX_BUF.store(0, Ordering::Relaxed);
+                TCP_RX_BUF.store(0, Ordering::Relaxed);
+                e
+            })
+        } else {
+            Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                "the socket service is already initialized",
+            ))
+        }
+    }
+}
+
+// Must be called with the state lock held.
+unsafe fn start(config: &SocketConfig) -> io::Result<()> {
+    let layout = Layout::from_size_align(config.pool_size, POOL_ALIGN)
+        .ok()
+        .filter(|layout| layout.size() > ALLOCATOR_SIZE)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "invalid socket pool size"))?;
+    let pool = alloc(layout);
+    if pool.is_null() {
+        return Err(io::Error::new(io::ErrorKind::Other, "failed to allocate the socket pool"));
+    }
+
+    let rc = Initialize(pool, layout.size(), ALLOCATOR_SIZE, config.concurrency as c_int) as i32;
+    STATE = match rc {
+        0 => State::Owned(pool, layout),
+        result::SOCKET_ALREADY_INITIALIZED => {
+            dealloc(pool, layout);
+            State::Borrowed
+        }
+        rc => {
+            dealloc(pool, layout);
+            return Err(io::Error::from_raw_os_error(rc));
+        }
+    };
+    INITIALIZED.store(true, Ordering::Release);
+    Ok(())
+}
+
+/// Finalizes the socket service if std initialized it. All sockets have to be
+/// closed by now.
+pub fn shutdown() -> io::Result<()> {
+    unsafe {
+        let _guard = STATE_LOCK.lock();
+        if let State::Owned(pool, layout) = STATE {
+            let rc = Finalize() as i32;
+            if rc != 0 {
+                return Err(io::Error::from_raw_os_error(rc));
+            }
+            dealloc(pool, layout);
+        }
+        STATE = State::Uninitialized;
+        INITIALIZED.store(false, Ordering::Release);
+        TCP_TX_BUF.store(0, Ordering::Relaxed);
+        TCP_RX_BUF.store(0, Ordering::Relaxed);
+    }
+    Ok(())
+}
 
 pub fn cvt_gai(err: c_int) -> io::Result<()> {
     if err == 0 {
@@ -64,6 +186,17 @@ impl Socket {
             #[cfg(target_vendor = "apple")]
             setsockopt(&socket, libc::SOL_SOCKET, libc::SO_NOSIGPIPE, 1)?;
 
+            if ty == libc::SOCK_STREAM {
+                let tx = TCP_TX_BUF.load(Ordering::Relaxed);
+                if tx != 0 {
+                    setsockopt(&socket, libc::SOL_SOCKET, libc::SO_SNDBUF, tx as c_int)?;
+                }
+                let rx = TCP_RX_BUF.load(Ordering::Relaxed);
+                if rx != 0 {
+                    setsockopt(&socket, libc::SOL_SOCKET, libc::SO_RCVBUF, rx as c_int)?;
+                }
+            }
+
             Ok(socket)
         }
     }
@@ -133,16 +266,23 @@ impl Socket {
                 }
                 0 => {}
                 _ => {
-                    // linux returns POLLOUT|POLLERR|POLLHUP for refused connections (!), so look
-                    // for POLLHUP rather than read readiness
-                    // if pollfd.revents & libc::POLLHUP != 0 {
-                    //     let e = self.take_error()?.unwrap_or_else(|| {
-                    //         io::Error::new(io::ErrorKind::Other, "no error set after POLLHUP")
-                    //     });
-                    //     return Err(e);
-                    // }
-
-                    return Ok(());
+                    // A failed connection attempt wakes us up as well, usually
+                    // with POLLOUT|POLLERR|POLLHUP, so it's only done once the
+                    // socket has no error pending. That error is the errno
+                    // connect(2) would have failed with, e.g. ECONNREFUSED or
+                    // EHOSTUNREACH.
+                    if let Some(e) = self.take_error()? {
+                        return Err(e);
+                    }
+                    if pollfd.revents & (libc::POLLERR | libc::POLLHUP | libc::POLLNVAL) != 0 {
+                        return Err(io::Error::new(
+                            io::ErrorKind::Other,
+                            "connection failed without an error set",
+                        ));
+                    }
+                    if pollfd.revents & libc::POLLOUT != 0 {
+                        return Ok(());
+                    }
                 }
             }
         }
@@ -275,8 +415,7 @@ impl Socket {
     }
 
     pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
-        let mut nonblocking = nonblocking as libc::c_int;
-        cvt(unsafe { libc::fcntl(*self.as_inner(), libc::O_NONBLOCK, &mut nonblocking) }).map(drop)
+        self.0.set_nonblocking(nonblocking)
     }
 
     pub fn take_error(&self) -> io::Result<Option<io::Error>> {
@@ -333,3 +472,36 @@ fn on_resolver_failure() {
 
 #[cfg(not(target_env = "gnu"))]
 fn on_resolver_failure() {}
+
+#[cfg(test)]
+mod tests {
+    use crate::io::{ErrorKind, Read, Write};
+    use crate::net::{TcpListener, TcpStream};
+    use crate::time::Duration;
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    #[test]
+    fn connect_timeout_accepting() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+
+        let mut client = TcpStream::connect_timeout(&addr, TIMEOUT).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+        client.write_all(b"hello").unwrap();
+        let mut buf = [0; 5];
+        server.read_exact(&mut buf).unwrap();
+        assert_eq!(&buf, b"hello");
+        assert!(client.take_error().unwrap().is_none());
+    }
+
+    #[test]
+    fn connect_timeout_refused() {
+        // Grab a free port and close it again, so nothing listens on it.
+        let addr = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap();
+
+        let err = TcpStream::connect_timeout(&addr, TIMEOUT).unwrap_err();
+        assert_eq!(err.kind(), ErrorKind::ConnectionRefused, "{}", err);
+        assert_eq!(err.raw_os_error(), Some(libc::ECONNREFUSED));
+    }
+}
@@ -1,10 +1,13 @@
 use crate::error::Error as StdError;
-use crate::ffi::{OsStr, OsString};
+use crate::ffi::{CStr, OsStr, OsString};
 use crate::fmt;
 use crate::io;
-use crate::path::{self, PathBuf};
+use crate::path::{self, Component, PathBuf};
 use crate::str;
+use crate::os::raw::{c_char, c_int};
+use crate::sys::result;
 use crate::sys::{unsupported, Void};
+use crate::sys_common::mutex::Mutex;
 
 pub fn errno() -> i32 {
     unsafe {
@@ -14,18 +17,91 @@ pub fn errno() -> i32 {
 
 pub fn error_string(errno: i32) -> String {
     if errno == 0 {
-        "operation successful".to_string()
-    } else {
-        "unknown error".to_string()
+        return "operation successful".to_string();
+    }
+    if result::is_result(errno) {
+        return result::to_string(errno);
+    }
+
+    extern "C" {
+        fn strerror(errnum: c_int) -> *const c_char;
     }
+
+    unsafe {
+        let p = strerror(errno as c_int);
+        if p.is_null() {
+            return "unknown error".to_string();
+        }
+        CStr::from_ptr(p).to_string_lossy().into_owned()
+    }
+}
+
+// There are no processes with a working directory of their own on the
+// switch, so the current directory is emulated for the whole module. It
+// starts out at the root of the SD card until someone changes it, unless std
+// was built with `SWITCH_DEFAULT_CWD` set to some other directory, e.g.
+// `rom:/`.
+const DEFAULT_CWD: &str = "sd:/";
+
+static CWD_LOCK: Mutex = Mutex::new();
+static mut CWD: Option<PathBuf> = None;
+
+fn default_cwd() -> PathBuf {
+    with_root(PathBuf::from(option_env!("SWITCH_DEFAULT_CWD").unwrap_or(DEFAULT_CWD)))
 }
 
 pub fn getcwd() -> io::Result<PathBuf> {
-    unsupported()
+    unsafe {
+        let _guard = CWD_LOCK.lock();
+        Ok(CWD.clone().unwrap_or_else(default_cwd))
+    }
 }
 
-pub fn chdir(_: &path::Path) -> io::Result<()> {
-    unsupported()
+pub fn chdir(p: &path::Path) -> io::Result<()> {
+    let p = absolute(p)?;
+    if !crate::sys::fs::stat(&p)?.file_type().is_dir() {
+        return Err(io::Error::new(io::ErrorKind::Other, "not a directory"));
+    }
+
+    unsafe {
+        let _guard = CWD_LOCK.lock();
+        CWD = Some(p);
+    }
+    Ok(())
+}
+
+/// Resolves a relative path against the current directory.
+pub fn absolute(p: &path::Path) -> io::Result<PathBuf> {
+    if p.is_absolute() {
+        Ok(p.to_path_buf())
+    } else {
+        Ok(with_root(join_cwd(&getcwd()?, p)))
+    }
+}
+
+// nn::fs rejects paths without a root after the mount name, so a bare mount
+// like `sd:` is taken to mean its root, as in `fs::canonicalize`.
+fn with_root(p: PathBuf) -> PathBuf {
+    let mut components = p.components();
+    let prefix = match components.next() {
+        Some(Component::Prefix(prefix)) => prefix,
+        _ => return p,
+    };
+    if components.clone().next() == Some(Component::RootDir) {
+        return p;
+    }
+
+    let mut rooted = prefix.as_os_str().to_os_string();
+    rooted.push("/");
+    let mut rooted = PathBuf::from(rooted);
+    rooted.extend(components);
+    rooted
+}
+
+fn join_cwd(cwd: &path::Path, p: &path::Path) -> PathBuf {
+    // Paths like `/atmosphere` are resolved against the mount the current
+    // directory is on, see `PathBuf::push`.
+    cwd.join(p)
 }
 
 pub struct SplitPaths<'a>(&'a Void);
@@ -115,3 +191,44 @@ pub fn getpid() -> u32 {
 pub fn page_size() -> usize {
     0x1000
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{join_cwd, with_root};
+    use crate::path::{Path, PathBuf};
+
+    fn join(cwd: &str, p: &str) -> String {
+        join_cwd(Path::new(cwd), Path::new(p)).to_str().unwrap().to_string()
+    }
+
+    #[test]
+    fn relative() {
+        assert_eq!(join("sd:/", "config.toml"), "sd:/config.toml");
+        assert_eq!(join("sd:/atmosphere", "contents/x"), "sd:/atmosphere/contents/x");
+        assert_eq!(join("rom:/data", "../ui"), "rom:/data/../ui");
+    }
+
+    #[test]
+    fn root_relative() {
+        assert_eq!(join("sd:/atmosphere/contents", "/switch"), "sd:/switch");
+        assert_eq!(join("rom:/", "/data/ui.arc"), "rom:/data/ui.arc");
+    }
+
+    #[test]
+    fn mount_prefixed() {
+        // A path on a mount of its own replaces the current directory, even
+        // when it has no root.
+        assert_eq!(join("sd:/atmosphere", "rom:/data.arc"), "rom:/data.arc");
+        assert_eq!(join("sd:/atmosphere", "save:"), "save:");
+        assert_eq!(join("sd:/atmosphere", "sd:config"), "sd:config");
+    }
+
+    #[test]
+    fn bare_mounts_get_a_root() {
+        let rooted = |p: &str| with_root(PathBuf::from(p)).to_str().unwrap().to_string();
+        assert_eq!(rooted("sd:"), "sd:/");
+        assert_eq!(rooted("sd:config"), "sd:/config");
+        assert_eq!(rooted("sd:/atmosphere"), "sd:/atmosphere");
+        assert_eq!(rooted("rom:/"), "rom:/");
+    }
+}
@@ -1,4 +1,5 @@
 use crate::ffi::OsStr;
+use crate::os::switch::ffi::OsStrExt;
 use crate::path::Prefix;
 
 #[inline]
@@ -11,8 +12,15 @@ pub fn is_verbatim_sep(b: u8) -> bool {
     b == b'/'
 }
 
-pub fn parse_prefix(_: &OsStr) -> Option<Prefix<'_>> {
-    None
+// nn::fs paths start with the name of the mount they are on, followed by a
+// colon, e.g. `sd:/atmosphere` or `rom:/data.arc`.
+pub fn parse_prefix(path: &OsStr) -> Option<Prefix<'_>> {
+    let bytes = path.as_bytes();
+    let colon = bytes.iter().position(|&b| b == b':' || is_sep_byte(b))?;
+    if colon == 0 || bytes[colon] != b':' {
+        return None;
+    }
+    Some(Prefix::Mount(OsStr::from_bytes(&bytes[..colon])))
 }
 
 pub const MAIN_SEP_STR: &str = "/";
@@ -0,0 +1,218 @@
+//! Decoding of nn::Result codes.
+//!
+//! Horizon reports failures as a 32-bit result code made up of the module
+//! that raised it (low 9 bits) and a module-specific description (the 13 bits
+//! above that). Errors coming out of the socket layer are plain errno values
+//! instead, but those always fit into the module bits with a description of
+//! zero, which no failing result has, so both can share `io::Error`'s raw OS
+//! error code.
+
+use crate::io::ErrorKind;
+
+pub const MODULE_KERNEL: u32 = 1;
+pub const MODULE_FS: u32 = 2;
+pub const MODULE_OS: u32 = 3;
+pub const MODULE_SOCKET: u32 = 27;
+
+/// `nn::fs::ResultPathNotFound`
+pub const FS_PATH_NOT_FOUND: i32 = make(MODULE_FS, 1);
+/// `nn::fs::ResultPathAlreadyExists`
+pub const FS_PATH_ALREADY_EXISTS: i32 = make(MODULE_FS, 2);
+/// `nn::fs::ResultMountNameAlreadyExists`
+pub const FS_MOUNT_NAME_ALREADY_EXISTS: i32 = make(MODULE_FS, 60);
+/// `nn::socket::ResultAlreadyInitialized`
+pub const SOCKET_ALREADY_INITIALIZED: i32 = make(MODULE_SOCKET, 3);
+
+pub const fn make(module: u32, description: u32) -> i32 {
+    (module | description << 9) as i32
+}
+
+/// Whether a raw OS error code is an nn::Result rather than an errno value.
+pub fn is_result(code: i32) -> bool {
+    description(code) != 0
+}
+
+pub fn module(code: i32) -> u32 {
+    code as u32 & 0x1ff
+}
+
+pub const fn description(code: i32) -> u32 {
+    (code as u32 >> 9) & 0x1fff
+}
+
+// Inclusive ranges of descriptions of a module. Lookups take the first entry
+// that matches, so specific results have to come before the range they are
+// part of.
+struct Desc(u32, u32, &'static str, ErrorKind);
+
+impl Desc {
+    // Entry for exactly one of the results above, so the table can't drift
+    // from the constants.
+    const fn result(code: i32, name: &'static str, kind: ErrorKind) -> Desc {
+        Desc(description(code), description(code), name, kind)
+    }
+}
+
+const KERNEL: &[Desc] = &[
+    Desc(7, 7, "OutOfSessions", ErrorKind::Other),
+    Desc(14, 14, "InvalidArgument", ErrorKind::InvalidInput),
+    Desc(33, 33, "NotImplemented", ErrorKind::Other),
+    Desc(59, 59, "TerminationRequested", ErrorKind::Interrupted),
+    Desc(101, 101, "InvalidSize", ErrorKind::InvalidInput),
+    Desc(102, 102, "InvalidAddress", ErrorKind::InvalidInput),
+    Desc(103, 103, "OutOfResource", ErrorKind::Other),
+    Desc(104, 104, "OutOfMemory", ErrorKind::Other),
+    Desc(105, 105, "OutOfHandles", ErrorKind::Other),
+    Desc(106, 106, "InvalidCurrentMemory", ErrorKind::InvalidInput),
+    Desc(108, 108, "InvalidNewMemoryPermission", ErrorKind::InvalidInput),
+    Desc(110, 110, "InvalidMemoryRegion", ErrorKind::InvalidInput),
+    Desc(112, 112, "InvalidPriority", ErrorKind::InvalidInput),
+    Desc(113, 113, "InvalidCoreId", ErrorKind::InvalidInput),
+    Desc(114, 114, "InvalidHandle", ErrorKind::InvalidInput),
+    Desc(115, 115, "InvalidPointer", ErrorKind::InvalidInput),
+    Desc(116, 116, "InvalidCombination", ErrorKind::InvalidInput),
+    Desc(117, 117, "TimedOut", ErrorKind::TimedOut),
+    Desc(118, 118, "Cancelled", ErrorKind::Interrupted),
+    Desc(119, 119, "OutOfRange", ErrorKind::InvalidInput),
+    Desc(120, 120, "InvalidEnumValue", ErrorKind::InvalidInput),
+    Desc(121, 121, "NotFound", ErrorKind::NotFound),
+    Desc(122, 122, "Busy", ErrorKind::WouldBlock),
+    Desc(123, 123, "SessionClosed", ErrorKind::BrokenPipe),
+    Desc(125, 125, "InvalidState", ErrorKind::Other),
+    Desc(127, 127, "NotSupported", ErrorKind::Other),
+    Desc(131, 131, "PortClosed", ErrorKind::BrokenPipe),
+    Desc(132, 132, "LimitReached", ErrorKind::Other),
+    Desc(259, 259, "OutOfAddressSpace", ErrorKind::Other),
+    Desc(260, 260, "MessageTooLarge", ErrorKind::InvalidInput),
+    Desc(523, 523, "ProcessTerminated", ErrorKind::Other),
+];
+
+const FS: &[Desc] = &[
+    Desc::result(FS_PATH_NOT_FOUND, "PathNotFound", ErrorKind::NotFound),
+    Desc::result(FS_PATH_ALREADY_EXISTS, "PathAlreadyExists", ErrorKind::AlreadyExists),
+    Desc(7, 7, "TargetLocked", ErrorKind::PermissionDenied),
+    Desc(8, 8, "DirectoryNotEmpty", ErrorKind::Other),
+    Desc(13, 13, "DirectoryStatusChanged", ErrorKind::Other),
+    // There is no `ErrorKind` for a full storage device (yet).
+    Desc(30, 45, "NotEnoughFreeSpace", ErrorKind::Other),
+    Desc::result(FS_MOUNT_NAME_ALREADY_EXISTS, "MountNameAlreadyExists", ErrorKind::AlreadyExists),
+    Desc(2001, 2001, "SdCardNotPresent", ErrorKind::NotFound),
+    Desc(2000, 2499, "SdCardAccessFailed", ErrorKind::Other),
+    Desc(3001, 3001, "NotImplemented", ErrorKind::Other),
+    Desc(3005, 3005, "OutOfRange", ErrorKind::InvalidInput),
+    Desc(3200, 3499, "AllocationFailure", ErrorKind::Other),
+    Desc(4000, 4999, "DataCorrupted", ErrorKind::InvalidData),
+    Desc(5000, 5999, "Unexpected", ErrorKind::Other),
+    Desc(6003, 6003, "TooLongPath", ErrorKind::InvalidInput),
+    Desc(6004, 6004, "InvalidCharacter", ErrorKind::InvalidInput),
+    Desc(6005, 6005, "InvalidPathFormat", ErrorKind::InvalidInput),
+    Desc(6006, 6006, "DirectoryUnobtainable", ErrorKind::InvalidInput),
+    Desc(6007, 6007, "NotNormalized", ErrorKind::InvalidInput),
+    Desc(6001, 6199, "InvalidArgument", ErrorKind::InvalidInput),
+    Desc(6202, 6202, "ReadNotPermitted", ErrorKind::PermissionDenied),
+    Desc(6203, 6203, "WriteNotPermitted", ErrorKind::PermissionDenied),
+    Desc(6200, 6299, "InvalidOperationForOpenMode", ErrorKind::PermissionDenied),
+    Desc(6300, 6399, "UnsupportedOperation", ErrorKind::Other),
+    Desc(6400, 6449, "PermissionDenied", ErrorKind::PermissionDenied),
+    Desc(6457, 6457, "FileNotClosed", ErrorKind::Other),
+    Desc(6458, 6458, "DirectoryNotClosed", ErrorKind::Other),
+    Desc(6459, 6459, "WriteModeFileNotClosed", ErrorKind::Other),
+    Desc(6600, 6699, "NotFound", ErrorKind::NotFound),
+    Desc(6001, 6999, "PreconditionViolation", ErrorKind::InvalidInput),
+];
+
+const OS: &[Desc] = &[
+    Desc(4, 4, "Busy", ErrorKind::WouldBlock),
+    Desc(8, 8, "OutOfMemory", ErrorKind::Other),
+    Desc(9, 9, "OutOfResource", ErrorKind::Other),
+    Desc(12, 12, "OutOfVirtualAddressSpace", ErrorKind::Other),
+    Desc(13, 13, "ResourceLimit", ErrorKind::Other),
+    Desc(500, 500, "OutOfHandles", ErrorKind::Other),
+    Desc(501, 501, "InvalidHandle", ErrorKind::InvalidInput),
+    Desc(502, 502, "InvalidCurrentMemoryState", ErrorKind::InvalidInput),
+    Desc(1000, 1000, "NotImplemented", ErrorKind::Other),
+    Desc(1001, 1001, "NotSupported", ErrorKind::Other),
+];
+
+const SOCKET: &[Desc] = &[
+    Desc(1, 1, "ResourceAllocationFailed", ErrorKind::Other),
+    Desc(2, 2, "NotInitialized", ErrorKind::Other),
+    Desc::result(SOCKET_ALREADY_INITIALIZED, "AlreadyInitialized", ErrorKind::AlreadyExists),
+    Desc(4, 4, "Cancelled", ErrorKind::Interrupted),
+];
+
+fn lookup(code: i32) -> Option<&'static Desc> {
+    let table = match module(code) {
+        MODULE_KERNEL => KERNEL,
+        MODULE_FS => FS,
+        MODULE_OS => OS,
+        MODULE_SOCKET => SOCKET,
+        _ => return None,
+    };
+    let description = description(code);
+    table.iter().find(|desc| desc.0 <= description && description <= desc.1)
+}
+
+/// The name of a result, as used by the SDK, if it is a known one.
+pub fn name(code: i32) -> Option<&'static str> {
+    lookup(code).map(|desc| desc.2)
+}
+
+pub fn kind(code: i32) -> ErrorKind {
+    lookup(code).map_or(ErrorKind::Other, |desc| desc.3)
+}
+
+/// Formats a result the way Nintendo's error codes are usually written,
+/// e.g. `2002-0001: PathNotFound`.
+pub fn to_string(code: i32) -> String {
+    format!(
+        "{:04}-{:04}: {}",
+        2000 + module(code),
+        description(code),
+        name(code).unwrap_or("unknown result"),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn split() {
+        assert_eq!(module(0x202), MODULE_FS);
+        assert_eq!(description(0x202), 1);
+        assert_eq!(make(MODULE_FS, 1), 0x202);
+        assert_eq!(make(MODULE_KERNEL, 117), 0xea01);
+        assert!(is_result(FS_PATH_NOT_FOUND));
+        // errno values, e.g. ENOENT and ECONNREFUSED
+        assert!(!is_result(2));
+        assert!(!is_result(61));
+    }
+
+    #[test]
+    fn decode() {
+        let cases = [
+            (FS_PATH_NOT_FOUND, "2002-0001: PathNotFound", ErrorKind::NotFound),
+            (FS_PATH_ALREADY_EXISTS, "2002-0002: PathAlreadyExists", ErrorKind::AlreadyExists),
+            (make(MODULE_FS, 35), "2002-0035: NotEnoughFreeSpace", ErrorKind::Other),
+            (make(MODULE_FS, 6004), "2002-6004: InvalidCharacter", ErrorKind::InvalidInput),
+            (make(MODULE_FS, 6150), "2002-6150: InvalidArgument", ErrorKind::InvalidInput),
+            (make(MODULE_FS, 6203), "2002-6203: WriteNotPermitted", ErrorKind::PermissionDenied),
+            (
+                FS_MOUNT_NAME_ALREADY_EXISTS,
+                "2002-0060: MountNameAlreadyExists",
+                ErrorKind::AlreadyExists,
+            ),
+            (make(MODULE_FS, 6605), "2002-6605: NotFound", ErrorKind::NotFound),
+            (make(MODULE_KERNEL, 117), "2001-0117: TimedOut", ErrorKind::TimedOut),
+            (make(MODULE_OS, 4), "2003-0004: Busy", ErrorKind::WouldBlock),
+            (make(MODULE_SOCKET, 4), "2027-0004: Cancelled", ErrorKind::Interrupted),
+            (make(168, 2), "2168-0002: unknown result", ErrorKind::Other),
+        ];
+
+        for &(code, string, kind) in cases.iter() {
+            assert_eq!(to_string(code), string);
+            assert_eq!(super::kind(code), kind, "{}", string);
+        }
+    }
+}
@@ -1,69 +1,158 @@
 use crate::cell::UnsafeCell;
+use crate::sys::condvar::Condvar;
+use crate::sys::mutex::Mutex;
 
 pub struct RWLock {
-    mode: UnsafeCell<isize>,
+    lock: Mutex,
+    readers: Condvar,
+    writers: Condvar,
+    state: UnsafeCell<State>,
+}
+
+struct State {
+    // Number of threads currently holding a read lock.
+    active_readers: usize,
+    // Whether a thread currently holds the write lock.
+    active_writer: bool,
+    // Number of threads blocked in `write`.
+    waiting_writers: usize,
 }
 
 unsafe impl Send for RWLock {}
-unsafe impl Sync for RWLock {} // no threads on wasm
+unsafe impl Sync for RWLock {}
+
+// This rwlock is built on top of the switch mutex and condition variables. The
+// mutex protects the bookkeeping in `State`, while readers and writers block
+// on separate condition variables so that unlocking only wakes up the side
+// that can actually make progress.
+//
+// The lock prefers writers: as soon as a writer is waiting, new readers will
+// block until every pending writer has been serviced. This keeps a steady
+// stream of readers (e.g. every thread that panics takes the hook lock for
+// reading) from starving `set_hook`/`take_hook` forever. As with the other
+// platforms, recursively acquiring a read lock on the same thread may
+// therefore deadlock if a writer is queued in between.
 
 impl RWLock {
     pub const fn new() -> RWLock {
-        RWLock { mode: UnsafeCell::new(0) }
+        RWLock {
+            lock: Mutex::new(),
+            readers: Condvar::new(),
+            writers: Condvar::new(),
+            state: UnsafeCell::new(State::new()),
+        }
     }
 
     #[inline]
     pub unsafe fn read(&self) {
-        let mode = self.mode.get();
-        if *mode >= 0 {
-            *mode += 1;
-        } else {
-            rtabort!("rwlock locked for writing");
+        self.lock.lock();
+        while !(*self.state.get()).inc_readers() {
+            self.readers.wait(&self.lock);
         }
+        self.lock.unlock();
     }
 
     #[inline]
     pub unsafe fn try_read(&self) -> bool {
-        let mode = self.mode.get();
-        if *mode >= 0 {
-            *mode += 1;
-            true
-        } else {
-            false
-        }
+        self.lock.lock();
+        let ok = (*self.state.get()).inc_readers();
+        self.lock.unlock();
+        ok
     }
 
     #[inline]
     pub unsafe fn write(&self) {
-        let mode = self.mode.get();
-        if *mode == 0 {
-            *mode = -1;
-        } else {
-            rtabort!("rwlock locked for reading")
+        self.lock.lock();
+        let state = self.state.get();
+        (*state).waiting_writers += 1;
+        while !(*state).inc_writers() {
+            self.writers.wait(&self.lock);
         }
+        (*state).waiting_writers -= 1;
+        self.lock.unlock();
     }
 
     #[inline]
     pub unsafe fn try_write(&self) -> bool {
-        let mode = self.mode.get();
-        if *mode == 0 {
-            *mode = -1;
-            true
-        } else {
-            false
-        }
+        self.lock.lock();
+        let ok = (*self.state.get()).inc_writers();
+        self.lock.unlock();
+        ok
     }
 
     #[inline]
     pub unsafe fn read_unlock(&self) {
-        *self.mode.get() -= 1;
+        self.lock.lock();
+        let state = self.state.get();
+        if (*state).dec_readers() && (*state).waiting_writers > 0 {
+            self.writers.notify_one();
+        }
+        self.lock.unlock();
     }
 
     #[inline]
     pub unsafe fn write_unlock(&self) {
-        *self.mode.get() += 1;
+        self.lock.lock();
+        let state = self.state.get();
+        (*state).dec_writers();
+        if (*state).waiting_writers > 0 {
+            self.writers.notify_one();
+        } else {
+            self.readers.notify_all();
+        }
+        self.lock.unlock();
     }
 
     #[inline]
-    pub unsafe fn destroy(&self) {}
+    pub unsafe fn destroy(&self) {
+        self.lock.destroy();
+        self.readers.destroy();
+        self.writers.destroy();
+    }
+}
+
+impl State {
+    const fn new() -> State {
+        State { active_readers: 0, active_writer: false, waiting_writers: 0 }
+    }
+
+    fn inc_readers(&mut self) -> bool {
+        if self.active_writer || self.waiting_writers > 0 {
+            return false;
+        }
+        self.active_readers += 1;
+        true
+    }
+
+    fn inc_writers(&mut self) -> bool {
+        if self.active_writer || self.active_readers > 0 {
+            return false;
+        }
+        self.active_writer = true;
+        true
+    }
+
+    /// Returns whether this was the last active reader.
+    fn dec_readers(&mut self) -> bool {
+        if self.active_writer || self.active_readers == 0 {
+            invalid();
+        }
+        self.active_readers -= 1;
+        self.active_readers == 0
+    }
+
+    fn dec_writers(&mut self) {
+        if !self.active_writer {
+            invalid();
+        }
+        self.active_writer = false;
+    }
+}
+
+fn invalid() -> ! {
+    rtabort!("inconsistent rwlock");
 }
+
+#[cfg(test)]
+#[path = "rwlock_tests.rs"]
+mod tests;
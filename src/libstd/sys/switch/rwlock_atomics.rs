@@ -1,144 +1,130 @@
-use crate::cell::UnsafeCell;
-use crate::sys::condvar::Condvar;
-use crate::sys::mutex::Mutex;
+use crate::sync::atomic::{AtomicU32, Ordering::*};
+use crate::sys::futex::{futex_wait, futex_wake, futex_wake_all};
 
 pub struct RWLock {
-    lock: Mutex,
-    cond: Condvar,
-    state: UnsafeCell<State>,
+    // The top bit is set while the lock is held for writing, the remaining
+    // bits count the threads currently holding it for reading.
+    state: AtomicU32,
+    // Number of threads blocked in `write`. New readers back off while this
+    // is non-zero, which gives writers preference over readers.
+    writers_waiting: AtomicU32,
+    // Sequence counters that readers and writers sleep on respectively. They
+    // are bumped before waking anyone up, so a thread that read the counter
+    // before checking `state` can never miss the wakeup meant for it.
+    reader_wakeups: AtomicU32,
+    writer_wakeups: AtomicU32,
 }
 
-enum State {
-    Unlocked,
-    Reading(usize),
-    Writing,
-}
+const WRITE_LOCKED: u32 = 1 << 31;
+const MAX_READERS: u32 = WRITE_LOCKED - 1;
 
 unsafe impl Send for RWLock {}
 unsafe impl Sync for RWLock {}
 
-// This rwlock implementation is a relatively simple implementation which has a
-// condition variable for readers/writers as well as a mutex protecting the
-// internal state of the lock. A current downside of the implementation is that
-// unlocking the lock will notify *all* waiters rather than just readers or just
-// writers. This can cause lots of "thundering stampede" problems. While
-// hopefully correct this implementation is very likely to want to be changed in
-// the future.
+// Like the mutex, acquiring an uncontended rwlock for either reading or
+// writing is a single cmpxchg on `state`, and only threads that actually have
+// to block talk to the address arbiter. Unlocking only wakes up the side that
+// can make progress: the last reader out wakes a single writer, and a writer
+// hands the lock over to the next waiting writer if there is one and to every
+// waiting reader otherwise.
 
 impl RWLock {
     pub const fn new() -> RWLock {
-        RWLock { lock: Mutex::new(), cond: Condvar::new(), state: UnsafeCell::new(State::Unlocked) }
+        RWLock {
+            state: AtomicU32::new(0),
+            writers_waiting: AtomicU32::new(0),
+            reader_wakeups: AtomicU32::new(0),
+            writer_wakeups: AtomicU32::new(0),
+        }
     }
 
     #[inline]
     pub unsafe fn read(&self) {
-        self.lock.lock();
-        while !(*self.state.get()).inc_readers() {
-            self.cond.wait(&self.lock);
+        loop {
+            let seq = self.reader_wakeups.load(Acquire);
+            if self.try_read() {
+                return;
+            }
+            futex_wait(&self.reader_wakeups, seq, None);
         }
-        self.lock.unlock();
     }
 
     #[inline]
     pub unsafe fn try_read(&self) -> bool {
-        self.lock.lock();
-        let ok = (*self.state.get()).inc_readers();
-        self.lock.unlock();
-        return ok;
+        let mut state = self.state.load(Relaxed);
+        loop {
+            if state & WRITE_LOCKED != 0 || self.writers_waiting.load(Relaxed) != 0 {
+                return false;
+            }
+            if state == MAX_READERS {
+                rtabort!("too many active read locks on RwLock");
+            }
+            match self.state.compare_exchange_weak(state, state + 1, Acquire, Relaxed) {
+                Ok(_) => return true,
+                Err(s) => state = s,
+            }
+        }
     }
 
     #[inline]
     pub unsafe fn write(&self) {
-        self.lock.lock();
-        while !(*self.state.get()).inc_writers() {
-            self.cond.wait(&self.lock);
+        if self.try_write() {
+            return;
         }
-        self.lock.unlock();
+
+        // This and the failed `try_write` below pair up with the unlock paths,
+        // which release `state` before checking `writers_waiting`. All four
+        // accesses are `SeqCst` so that at least one side sees the other:
+        // either the unlocking thread sees us waiting and wakes us up, or we
+        // see the lock released and take it without going to sleep.
+        self.writers_waiting.fetch_add(1, SeqCst);
+        loop {
+            let seq = self.writer_wakeups.load(Acquire);
+            if self.try_write() {
+                break;
+            }
+            futex_wait(&self.writer_wakeups, seq, None);
+        }
+        self.writers_waiting.fetch_sub(1, Relaxed);
     }
 
     #[inline]
     pub unsafe fn try_write(&self) -> bool {
-        self.lock.lock();
-        let ok = (*self.state.get()).inc_writers();
-        self.lock.unlock();
-        return ok;
+        self.state.compare_exchange(0, WRITE_LOCKED, SeqCst, SeqCst).is_ok()
     }
 
     #[inline]
     pub unsafe fn read_unlock(&self) {
-        self.lock.lock();
-        let notify = (*self.state.get()).dec_readers();
-        self.lock.unlock();
-        if notify {
-            // FIXME: should only wake up one of these some of the time
-            self.cond.notify_all();
+        let prev = self.state.fetch_sub(1, SeqCst);
+        debug_assert!(prev & WRITE_LOCKED == 0 && prev != 0, "inconsistent rwlock");
+        if prev == 1 && self.writers_waiting.load(SeqCst) != 0 {
+            self.wake_writer();
         }
     }
 
     #[inline]
     pub unsafe fn write_unlock(&self) {
-        self.lock.lock();
-        (*self.state.get()).dec_writers();
-        self.lock.unlock();
-        // FIXME: should only wake up one of these some of the time
-        self.cond.notify_all();
+        let prev = self.state.swap(0, SeqCst);
+        debug_assert_eq!(prev, WRITE_LOCKED, "inconsistent rwlock");
+        if self.writers_waiting.load(SeqCst) != 0 {
+            self.wake_writer();
+        } else {
+            self.reader_wakeups.fetch_add(1, Release);
+            futex_wake_all(&self.reader_wakeups);
+        }
     }
 
     #[inline]
     pub unsafe fn destroy(&self) {
-        self.lock.destroy();
-        self.cond.destroy();
+        // nothing to do
     }
-}
 
-impl State {
-    fn inc_readers(&mut self) -> bool {
-        match *self {
-            State::Unlocked => {
-                *self = State::Reading(1);
-                true
-            }
-            State::Reading(ref mut cnt) => {
-                *cnt += 1;
-                true
-            }
-            State::Writing => false,
-        }
-    }
-
-    fn inc_writers(&mut self) -> bool {
-        match *self {
-            State::Unlocked => {
-                *self = State::Writing;
-                true
-            }
-            State::Reading(_) | State::Writing => false,
-        }
-    }
-
-    fn dec_readers(&mut self) -> bool {
-        let zero = match *self {
-            State::Reading(ref mut cnt) => {
-                *cnt -= 1;
-                *cnt == 0
-            }
-            State::Unlocked | State::Writing => invalid(),
-        };
-        if zero {
-            *self = State::Unlocked;
-        }
-        zero
-    }
-
-    fn dec_writers(&mut self) {
-        match *self {
-            State::Writing => {}
-            State::Unlocked | State::Reading(_) => invalid(),
-        }
-        *self = State::Unlocked;
+    fn wake_writer(&self) {
+        self.writer_wakeups.fetch_add(1, Release);
+        futex_wake(&self.writer_wakeups, 1);
     }
 }
 
-fn invalid() -> ! {
-    panic!("inconsistent rwlock");
-}
+#[cfg(test)]
+#[path = "rwlock_tests.rs"]
+mod tests;
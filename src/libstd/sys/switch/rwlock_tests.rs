@@ -0,0 +1,136 @@
+// Tests shared by both `RWLock` implementations, they only go through
+// `sync::RwLock` and are included by whichever one is built.
+
+use crate::sync::atomic::{AtomicUsize, Ordering};
+use crate::sync::{Arc, Barrier, RwLock};
+use crate::thread;
+
+#[test]
+fn stress_readers_and_writers() {
+    const READERS: usize = 8;
+    const WRITERS: usize = 4;
+    const ITERS: usize = 1000;
+
+    let lock = Arc::new(RwLock::new((0usize, 0usize)));
+    let barrier = Arc::new(Barrier::new(READERS + WRITERS));
+    let mut threads = Vec::new();
+
+    for _ in 0..WRITERS {
+        let lock = lock.clone();
+        let barrier = barrier.clone();
+        threads.push(thread::spawn(move || {
+            barrier.wait();
+            for _ in 0..ITERS {
+                let mut guard = lock.write().unwrap();
+                // Both halves are updated under the write lock, so a
+                // reader must never observe them out of sync.
+                guard.0 += 1;
+                thread::yield_now();
+                guard.1 += 1;
+            }
+        }));
+    }
+
+    for _ in 0..READERS {
+        let lock = lock.clone();
+        let barrier = barrier.clone();
+        threads.push(thread::spawn(move || {
+            barrier.wait();
+            for _ in 0..ITERS {
+                let guard = lock.read().unwrap();
+                assert_eq!(guard.0, guard.1);
+            }
+        }));
+    }
+
+    for t in threads {
+        t.join().unwrap();
+    }
+
+    let guard = lock.read().unwrap();
+    assert_eq!(*guard, (WRITERS * ITERS, WRITERS * ITERS));
+}
+
+#[test]
+fn concurrent_readers() {
+    const READERS: usize = 4;
+
+    let lock = Arc::new(RwLock::new(()));
+    let barrier = Arc::new(Barrier::new(READERS));
+    let inside = Arc::new(AtomicUsize::new(0));
+
+    let threads: Vec<_> = (0..READERS)
+        .map(|_| {
+            let lock = lock.clone();
+            let barrier = barrier.clone();
+            let inside = inside.clone();
+            thread::spawn(move || {
+                let _guard = lock.read().unwrap();
+                inside.fetch_add(1, Ordering::SeqCst);
+                // Every reader has to be inside the lock at the same time
+                // to get past the barrier, otherwise this deadlocks.
+                barrier.wait();
+            })
+        })
+        .collect();
+
+    for t in threads {
+        t.join().unwrap();
+    }
+    assert_eq!(inside.load(Ordering::SeqCst), READERS);
+}
+
+#[test]
+fn writer_preference() {
+    let lock = Arc::new(RwLock::new(()));
+    let read = lock.read().unwrap();
+
+    let writer = {
+        let lock = lock.clone();
+        thread::spawn(move || drop(lock.write().unwrap()))
+    };
+
+    // Wait for the writer to queue up behind our read lock, after which
+    // new readers must be turned away.
+    while lock.try_read().is_ok() {
+        thread::yield_now();
+    }
+
+    drop(read);
+    writer.join().unwrap();
+    drop(lock.try_read().unwrap());
+}
+
+#[test]
+fn writer_wakes_up_after_last_reader() {
+    const ITERS: usize = 1000;
+
+    // A single reader and a single writer keep handing the lock to each
+    // other, which is exactly the window where a writer going to sleep
+    // can miss the wakeup from the reader leaving. This deadlocks if it
+    // ever does.
+    let lock = Arc::new(RwLock::new(0usize));
+    let reads = Arc::new(AtomicUsize::new(0));
+
+    let writer = {
+        let lock = lock.clone();
+        let reads = reads.clone();
+        thread::spawn(move || {
+            for i in 0..ITERS {
+                while reads.load(Ordering::SeqCst) <= i {
+                    thread::yield_now();
+                }
+                *lock.write().unwrap() += 1;
+            }
+        })
+    };
+
+    for i in 0..ITERS {
+        let guard = lock.read().unwrap();
+        reads.fetch_add(1, Ordering::SeqCst);
+        assert!(*guard <= i);
+    }
+
+    writer.join().unwrap();
+    assert_eq!(*lock.read().unwrap(), ITERS);
+}
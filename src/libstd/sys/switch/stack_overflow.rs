@@ -1,3 +1,163 @@
+#![cfg_attr(test, allow(dead_code))]
+
+use crate::sync::atomic::{AtomicBool, Ordering};
+use crate::sys_common::thread_info;
+
+// Register state nn::os hands to a user exception handler. Only the fault
+// address is of interest to us, the rest is spelled out to get the layout
+// right.
+#[repr(C)]
+#[allow(dead_code)]
+struct UserExceptionInfo {
+    error_description: u32,
+    _reserved: [u32; 3],
+    cpu_registers: [u64; 29],
+    fp: u64,
+    lr: u64,
+    sp: u64,
+    pc: u64,
+    pstate: u32,
+    afsr0: u32,
+    afsr1: u32,
+    esr: u32,
+    far: u64,
+}
+
+extern "C" {
+    // nn::os::SetUserExceptionHandler(void (*)(nn::os::UserExceptionInfo*), void*, unsigned long,
+    //                                 nn::os::UserExceptionInfo*)
+    #[link_name = "_ZN2nn2os23SetUserExceptionHandlerEPFvPNS0_17UserExceptionInfoEEPvmS2_"]
+    fn SetUserExceptionHandler(
+        handler: Option<unsafe extern "C" fn(*mut UserExceptionInfo)>,
+        stack: *mut u8,
+        stack_size: usize,
+        info: *mut UserExceptionInfo,
+    );
+}
+
+// The handler has to run on a stack of its own, the faulting thread's stack
+// is exhausted after all.
+const HANDLER_STACK_SIZE: usize = 0x4000;
+
+#[repr(C, align(4096))]
+struct HandlerStack([u8; HANDLER_STACK_SIZE]);
+
+static mut HANDLER_STACK: HandlerStack = HandlerStack([0; HANDLER_STACK_SIZE]);
+static mut EXCEPTION_INFO: UserExceptionInfo = UserExceptionInfo {
+    error_description: 0,
+    _reserved: [0; 3],
+    cpu_registers: [0; 29],
+    fp: 0,
+    lr: 0,
+    sp: 0,
+    pc: 0,
+    pstate: 0,
+    afsr0: 0,
+    afsr1: 0,
+    esr: 0,
+    far: 0,
+};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+
+// Exception handler for the whole process. Threads spawned by std have an
+// unmapped guard page right below their stack, so if a thread ends up running
+// into the guard page it'll fault and end up in here. We want to detect these
+// cases and print out a helpful error saying that the stack has overflowed
+// before aborting, instead of leaving the user with a bare data abort.
+//
+// If this is not a stack overflow the handler just returns, after which nn::os
+// reports the exception as unhandled and the usual crash handling takes over.
+unsafe extern "C" fn exception_handler(info: *mut UserExceptionInfo) {
+    use crate::sys_common::util::report_overflow;
+
+    let guard = thread_info::stack_guard().unwrap_or(0..0);
+    let addr = (*info).far as usize;
+
+    // If the faulting address is within the guard page, then we print a
+    // message saying so and abort.
+    if guard.start <= addr && addr < guard.end {
+        #[cfg(test)]
+        tests::overflowed();
+        report_overflow();
+        rtabort!("stack overflow");
+    }
+}
+
+// The handler is process-wide, so installing it would throw out whatever
+// handler the game or another plugin set up. nn::os has no way to get at the
+// previous handler to chain to it either, so it is only ever installed when
+// asked to, see `os::switch::thread::install_stack_overflow_handler`.
 pub unsafe fn init() {}
 
-pub unsafe fn cleanup() {}
+pub fn install() {
+    if INSTALLED.swap(true, Ordering::SeqCst) {
+        return;
+    }
+
+    unsafe {
+        SetUserExceptionHandler(
+            Some(exception_handler),
+            HANDLER_STACK.0.as_mut_ptr(),
+            HANDLER_STACK_SIZE,
+            &mut EXCEPTION_INFO,
+        );
+    }
+}
+
+pub fn uninstall() {
+    if !INSTALLED.swap(false, Ordering::SeqCst) {
+        return;
+    }
+
+    unsafe {
+        SetUserExceptionHandler(None, crate::ptr::null_mut(), 0, crate::ptr::null_mut());
+    }
+}
+
+// The handler and its stack are part of our image, so they must not be left
+// installed once std is torn down.
+pub unsafe fn cleanup() {
+    uninstall();
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::ptr;
+    use crate::sync::atomic::{AtomicBool, Ordering};
+    use crate::sys_common::thread_info;
+    use crate::thread;
+    use crate::time::{Duration, Instant};
+
+    static OVERFLOWED: AtomicBool = AtomicBool::new(false);
+
+    // Called by the handler instead of aborting. The faulting thread can't
+    // go on, so it is parked here for good, still holding on to its stack.
+    pub fn overflowed() -> ! {
+        OVERFLOWED.store(true, Ordering::SeqCst);
+        loop {
+            thread::sleep(Duration::from_secs(3600));
+        }
+    }
+
+    #[test]
+    fn guard_page_fault_is_detected() {
+        super::install();
+
+        thread::spawn(|| {
+            let guard = thread_info::stack_guard().expect("no guard for a std thread");
+            unsafe {
+                ptr::read_volatile(guard.start as *const u8);
+            }
+            unreachable!("the guard page is accessible");
+        });
+
+        let start = Instant::now();
+        while !OVERFLOWED.load(Ordering::SeqCst) {
+            assert!(start.elapsed() < Duration::from_secs(5), "guard page fault went undetected");
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        super::uninstall();
+    }
+}
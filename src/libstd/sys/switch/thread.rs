@@ -1,100 +1,317 @@
+use crate::alloc::{alloc, dealloc, Layout};
+use crate::cell::UnsafeCell;
 use crate::cmp;
-use crate::ffi::CStr;
+use crate::ffi::{CStr, CString};
 use crate::io;
 use crate::mem;
-use crate::ptr;
+use crate::sync::atomic::{AtomicBool, Ordering};
+use crate::sys::fast_thread_local;
 use crate::sys::os;
-use crate::time::Duration;
+use crate::sys_common::condvar::Condvar;
+use crate::sys_common::mutex::Mutex;
+use crate::time::{Duration, Instant};
 
+use nnsdk::os::ThreadType;
 use nnsdk::{os::SleepThread, TimeSpan};
 
-#[cfg(not(target_os = "l4re"))]
 pub const DEFAULT_MIN_STACK_SIZE: usize = 2 * 1024 * 1024;
-#[cfg(target_os = "l4re")]
-pub const DEFAULT_MIN_STACK_SIZE: usize = 1024 * 1024;
+
+// Priority given to threads that don't ask for one, the same as
+// nn::os::DefaultThreadPriority.
+pub const DEFAULT_PRIORITY: i32 = 16;
+// Valid priorities for user threads, from highest to lowest.
+pub const HIGHEST_PRIORITY: i32 = 0;
+pub const LOWEST_PRIORITY: i32 = 31;
+
+// Special values nn::os accepts in place of an ideal core.
+const IDEAL_CORE_USE_DEFAULT: i32 = -2;
+const IDEAL_CORE_NO_UPDATE: i32 = -3;
+
+// nn::os requires thread stacks to be page aligned and a multiple of the page
+// size.
+const STACK_ALIGN: usize = 0x1000;
+
+/// Options for spawning a thread beyond what `thread::Builder` offers on every
+/// platform, set through `std::os::switch::thread::BuilderExt`.
+#[derive(Debug)]
+pub struct SpawnOptions {
+    pub priority: Option<i32>,
+    pub ideal_core: Option<i32>,
+    pub affinity_mask: Option<u64>,
+    pub stack: Option<(*mut u8, usize)>,
+}
+
+// The only pointer in here is caller-provided stack memory, which is handed to
+// us as a `&'static mut [u8]`.
+unsafe impl Send for SpawnOptions {}
+unsafe impl Sync for SpawnOptions {}
+
+impl SpawnOptions {
+    pub const fn new() -> SpawnOptions {
+        SpawnOptions { priority: None, ideal_core: None, affinity_mask: None, stack: None }
+    }
+}
+
+// Stacks we allocate ourselves carry an extra page at their low end which is
+// made inaccessible for as long as the thread lives, so that running off the
+// end of the stack faults instead of silently corrupting the heap.
+enum Stack {
+    Owned { ptr: *mut u8, layout: Layout },
+    Borrowed { ptr: *mut u8, size: usize },
+}
+
+impl Stack {
+    unsafe fn allocate(size: usize) -> io::Result<Stack> {
+        let layout = size
+            .checked_add(guard::PAGE_SIZE)
+            .and_then(|size| Layout::from_size_align(size, STACK_ALIGN).ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "stack too large"))?;
+        let ptr = alloc(layout);
+        if ptr.is_null() {
+            return Err(io::Error::new(io::ErrorKind::Other, "failed to allocate thread stack"));
+        }
+        if let Err(e) = guard::protect(ptr) {
+            dealloc(ptr, layout);
+            return Err(e);
+        }
+        Ok(Stack::Owned { ptr, layout })
+    }
+
+    fn ptr(&self) -> *mut u8 {
+        match *self {
+            Stack::Owned { ptr, .. } => ptr.wrapping_add(guard::PAGE_SIZE),
+            Stack::Borrowed { ptr, .. } => ptr,
+        }
+    }
+
+    fn size(&self) -> usize {
+        match *self {
+            Stack::Owned { layout, .. } => layout.size() - guard::PAGE_SIZE,
+            Stack::Borrowed { size, .. } => size,
+        }
+    }
+}
+
+impl Drop for Stack {
+    fn drop(&mut self) {
+        if let Stack::Owned { ptr, layout } = *self {
+            unsafe {
+                // The guard page has to be accessible again before the
+                // allocator gets to reuse it.
+                guard::unprotect(ptr);
+                dealloc(ptr, layout)
+            }
+        }
+    }
+}
+
+// Everything the kernel thread needs for as long as it runs. This is boxed so
+// that its address stays put for the new thread no matter what happens to the
+// `Thread` handle.
+struct Native {
+    thread: UnsafeCell<ThreadType>,
+    stack: Stack,
+    finished: AtomicBool,
+    // Signalled together with `finished`, for joins with a timeout.
+    lock: Mutex,
+    cvar: Condvar,
+}
+
+impl Native {
+    fn raw(&self) -> *mut ThreadType {
+        self.thread.get()
+    }
+
+    // Waits at most `dur` for the thread to finish running, returns whether
+    // it did.
+    unsafe fn wait_finished(&self, dur: Duration) -> bool {
+        let deadline = Instant::now().checked_add(dur);
+        let _lock = self.lock.lock();
+        while !self.finished.load(Ordering::Acquire) {
+            let now = Instant::now();
+            let timeout = match deadline {
+                Some(deadline) if deadline <= now => return false,
+                Some(deadline) => deadline - now,
+                None => Duration::from_secs(u64::max_value()),
+            };
+            self.cvar.wait_timeout(&self.lock, timeout);
+        }
+        true
+    }
+
+    unsafe fn set_finished(&self) {
+        let _lock = self.lock.lock();
+        self.finished.store(true, Ordering::Release);
+        self.cvar.notify_all();
+    }
+
+    // Blocks until the thread has exited and releases its kernel object and
+    // stack.
+    unsafe fn reap(self: Box<Native>) {
+        nnsdk::os::WaitThread(self.raw());
+        nnsdk::os::DestroyThread(self.raw());
+    }
+}
+
+impl Drop for Native {
+    fn drop(&mut self) {
+        unsafe {
+            self.cvar.destroy();
+            self.lock.destroy();
+        }
+    }
+}
+
+// nn::os has no notion of detaching a thread: its `ThreadType` and stack have
+// to stay alive until the thread has exited, and someone else has to destroy
+// it afterwards. Threads whose `Thread` handle is dropped without joining are
+// therefore parked here and cleaned up once they have finished, the next time
+// a thread is spawned or detached, or when std is torn down.
+static DETACHED_LOCK: Mutex = Mutex::new();
+static mut DETACHED: Vec<Box<Native>> = Vec::new();
+
+unsafe fn reap_detached() {
+    let finished = {
+        let _lock = DETACHED_LOCK.lock();
+        let (finished, running) = mem::take(&mut DETACHED)
+            .into_iter()
+            .partition(|native| native.finished.load(Ordering::Acquire));
+        DETACHED = running;
+        finished
+    };
+    for native in finished {
+        native.reap();
+    }
+}
 
 pub struct Thread {
-    id: libc::pthread_t,
+    native: Option<Box<Native>>,
 }
 
-// Some platforms may have pthread_t as a pointer in which case we still want
-// a thread to be Send/Sync
 unsafe impl Send for Thread {}
 unsafe impl Sync for Thread {}
 
-unsafe fn pthread_attr_setstacksize(
-    attr: *mut libc::pthread_attr_t,
-    stack_size: libc::size_t,
-) -> libc::c_int {
-    libc::pthread_attr_setstacksize(attr, stack_size)
+struct Start {
+    main: Box<dyn FnOnce()>,
+    native: *const Native,
 }
 
 impl Thread {
     // unsafe: see thread::Builder::spawn_unchecked for safety requirements
     pub unsafe fn new(stack: usize, p: Box<dyn FnOnce()>) -> io::Result<Thread> {
-        let p = Box::into_raw(box p);
-        let mut native: libc::pthread_t = mem::zeroed();
-        let mut attr: libc::pthread_attr_t = mem::zeroed();
-        assert_eq!(libc::pthread_attr_init(&mut attr), 0);
-
-        let stack_size = cmp::max(stack, min_stack_size(&attr));
-
-        match pthread_attr_setstacksize(&mut attr, stack_size) {
-            0 => {}
-            n => {
-                assert_eq!(n, libc::EINVAL);
-                // EINVAL means |stack_size| is either too small or not a
-                // multiple of the system page size.  Because it's definitely
-                // >= PTHREAD_STACK_MIN, it must be an alignment issue.
-                // Round up to the nearest page and try again.
-                let page_size = os::page_size();
-                let stack_size =
-                    (stack_size + page_size - 1) & (-(page_size as isize - 1) as usize - 1);
-                assert_eq!(libc::pthread_attr_setstacksize(&mut attr, stack_size), 0);
+        Thread::new_with_options(stack, &SpawnOptions::new(), p)
+    }
+
+    // unsafe: see thread::Builder::spawn_unchecked for safety requirements
+    pub unsafe fn new_with_options(
+        stack: usize,
+        options: &SpawnOptions,
+        p: Box<dyn FnOnce()>,
+    ) -> io::Result<Thread> {
+        reap_detached();
+
+        let priority = options.priority.unwrap_or(DEFAULT_PRIORITY);
+        if priority < HIGHEST_PRIORITY || priority > LOWEST_PRIORITY {
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, "invalid thread priority"));
+        }
+
+        let stack = match options.stack {
+            Some((ptr, size)) => {
+                if ptr as usize % STACK_ALIGN != 0 || size % STACK_ALIGN != 0 || size == 0 {
+                    return Err(io::Error::new(
+                        io::ErrorKind::InvalidInput,
+                        "thread stack memory must be page aligned and a multiple of the page size",
+                    ));
+                }
+                Stack::Borrowed { ptr, size }
+            }
+            None => {
+                let size = cmp::max(stack, min_stack_size());
+                Stack::allocate((size + STACK_ALIGN - 1) & !(STACK_ALIGN - 1))?
             }
         };
 
-        let ret = libc::pthread_create(&mut native, &attr, thread_start, p as *mut _);
-        // Note: if the thread creation fails and this assert fails, then p will
-        // be leaked. However, an alternative design could cause double-free
-        // which is clearly worse.
-        assert_eq!(libc::pthread_attr_destroy(&mut attr), 0);
-
-        return if ret != 0 {
-            // The thread failed to start and as a result p was not consumed. Therefore, it is
-            // safe to reconstruct the box so that it gets deallocated.
-            drop(Box::from_raw(p));
-            Err(io::Error::from_raw_os_error(ret))
-        } else {
-            Ok(Thread { id: native })
-        };
+        let mut native = Box::new(Native {
+            thread: UnsafeCell::new(mem::zeroed()),
+            stack,
+            finished: AtomicBool::new(false),
+            lock: Mutex::new(),
+            cvar: Condvar::new(),
+        });
+        native.lock.init();
+        native.cvar.init();
+
+        let start = Box::into_raw(box Start { main: p, native: &*native });
+
+        let rc = nnsdk::os::CreateThread(
+            native.raw(),
+            Some(thread_start),
+            start as *mut _,
+            native.stack.ptr() as *mut _,
+            native.stack.size() as _,
+            priority,
+            options.ideal_core.unwrap_or(IDEAL_CORE_USE_DEFAULT),
+        );
+
+        if rc != 0 {
+            // The thread failed to start and as a result `start` was not
+            // consumed. Therefore, it is safe to reconstruct the box so that
+            // it gets deallocated.
+            drop(Box::from_raw(start));
+            return Err(io::Error::from_raw_os_error(rc as i32));
+        }
+
+        if let Some(mask) = options.affinity_mask {
+            let ideal_core = options.ideal_core.unwrap_or(IDEAL_CORE_NO_UPDATE);
+            let rc = nnsdk::os::SetThreadCoreMask(native.raw(), ideal_core, mask);
+            if rc != 0 {
+                // The thread was created but never started, so it can be
+                // destroyed right away and `start` was not consumed either.
+                nnsdk::os::DestroyThread(native.raw());
+                drop(Box::from_raw(start));
+                return Err(io::Error::from_raw_os_error(rc as i32));
+            }
+        }
+
+        nnsdk::os::StartThread(native.raw());
 
-        extern "C" fn thread_start(main: *mut libc::c_void) -> *mut libc::c_void {
+        return Ok(Thread { native: Some(native) });
+
+        extern "C" fn thread_start(start: *mut libc::c_void) {
             unsafe {
-                // Next, set up our stack overflow handler which may get triggered if we run
-                // out of stack.
-                //let _handler = stack_overflow::Handler::new();
+                let start = Box::from_raw(start as *mut Start);
+                let Start { main, native } = *start;
+                if let Stack::Owned { ptr, .. } = (*native).stack {
+                    guard::set_own_stack(ptr as usize, (*native).stack.size());
+                }
                 // Finally, let's run some code.
-                Box::from_raw(main as *mut Box<dyn FnOnce()>)();
+                main();
+                // Thread-local destructors may still use the stack, so they
+                // have to run before a detached thread can be reaped.
+                fast_thread_local::run_dtors();
+                // That also dropped our `thread::Thread` handle, which might
+                // have been the last owner of the name we gave nn::os.
+                nnsdk::os::SetThreadNamePointer(
+                    nnsdk::os::GetCurrentThread(),
+                    EMPTY_NAME.as_ptr() as _,
+                );
+                // From here on the thread only has to return, so it's safe to
+                // let a detached thread be reaped.
+                (*native).set_finished();
             }
-            ptr::null_mut()
         }
     }
 
     pub fn yield_now() {
-        let ret = unsafe { libc::sched_yield() };
-        debug_assert_eq!(ret, 0);
+        unsafe { nnsdk::os::YieldThread() }
     }
 
     pub fn set_name(name: &CStr) {
-        use crate::ffi::CString;
-        let cname = CString::new(&b"%s"[..]).unwrap();
+        // nn::os only keeps the pointer, the name itself is owned by the
+        // `thread::Thread` of the calling thread which outlives this thread's
+        // use of it, see `thread_start`.
         unsafe {
-            libc::pthread_setname_np(
-                libc::pthread_self(),
-                cname.as_ptr(),
-                name.as_ptr() as *mut libc::c_void,
-            );
+            nnsdk::os::SetThreadNamePointer(nnsdk::os::GetCurrentThread(), name.as_ptr() as _);
         }
     }
 
@@ -106,44 +323,279 @@ impl Thread {
         }
     }
 
-    pub fn join(self) {
+    pub fn join(mut self) {
+        unsafe {
+            self.native.take().unwrap().reap();
+        }
+    }
+
+    /// Joins the thread if it finishes within `dur`, otherwise hands the
+    /// thread back.
+    pub fn join_timeout(mut self, dur: Duration) -> Result<(), Thread> {
         unsafe {
-            let ret = libc::pthread_join(self.id, ptr::null_mut());
-            mem::forget(self);
-            assert!(ret == 0, "failed to join thread: {}", io::Error::from_raw_os_error(ret));
+            if !self.native.as_ref().unwrap().wait_finished(dur) {
+                return Err(self);
+            }
+            self.native.take().unwrap().reap();
+            Ok(())
         }
     }
 
-    // pub fn id(&self) -> libc::pthread_t {
-    //     self.id
-    // }
+    pub fn as_raw(&self) -> *mut ThreadType {
+        self.native.as_ref().unwrap().raw()
+    }
+
+    /// Returns the kernel handle nn::os keeps for the thread.
+    pub fn handle(&self) -> io::Result<u32> {
+        match GetThreadHandle.get() {
+            Some(get) => Ok(unsafe { get(self.as_raw()) }),
+            None => Err(io::Error::new(io::ErrorKind::Other, "unsupported by this title")),
+        }
+    }
+}
 
-    // pub fn into_id(self) -> libc::pthread_t {
-    //     let id = self.id;
-    //     mem::forget(self);
-    //     id
-    // }
+// Not every title links this, see `sys::weak`. The returned `nn::svc::Handle`
+// is a plain 32-bit handle value.
+weak! {
+    #[link_name = "_ZN2nn2os15GetThreadHandleEPKNS0_10ThreadTypeE"]
+    fn GetThreadHandle(*const ThreadType) -> u32
 }
 
 impl Drop for Thread {
     fn drop(&mut self) {
-        let ret = unsafe { libc::pthread_detach(self.id) };
-        debug_assert_eq!(ret, 0);
+        if let Some(native) = self.native.take() {
+            unsafe {
+                reap_detached();
+                let _lock = DETACHED_LOCK.lock();
+                DETACHED.push(native);
+            }
+        }
     }
 }
 
+// Called when std is torn down, see `sys_common::cleanup`. Detached threads
+// that are still running by then keep their stack and `ThreadType`, as nothing
+// tells when they'd be done with them.
+pub unsafe fn cleanup() {
+    reap_detached();
+}
+
+static EMPTY_NAME: &[u8] = b"\0";
+
+/// Name and kernel thread id of the calling thread, used to adopt threads std
+/// didn't spawn the first time they call `thread::current()`.
+pub fn current_native() -> (Option<CString>, u64) {
+    unsafe {
+        let thread = nnsdk::os::GetCurrentThread();
+        let name = nnsdk::os::GetThreadNamePointer(thread);
+        let name = if name.is_null() {
+            None
+        } else {
+            Some(CStr::from_ptr(name as *const _))
+                .filter(|name| !name.to_bytes().is_empty())
+                .map(CStr::to_owned)
+        };
+        (name, nnsdk::os::GetThreadId(thread))
+    }
+}
+
+pub fn current_core() -> u32 {
+    unsafe { nnsdk::os::GetCurrentCoreNumber() as u32 }
+}
+
+pub fn available_cores() -> usize {
+    unsafe { nnsdk::os::GetThreadAvailableCoreMask().count_ones() as usize }
+}
+
+// This is only used by atomics primitives when the `atomics` feature is
+// enabled. Horizon gives every thread its own thread-local region and keeps
+// its address in TPIDRRO_EL0, which makes it a cheap, unique id for as long as
+// the thread is alive.
+#[cfg(target_feature = "atomics")]
+pub fn my_id() -> usize {
+    let tls: usize;
+    unsafe {
+        llvm_asm!("mrs $0, tpidrro_el0" : "=r"(tls) ::: "volatile");
+    }
+    tls
+}
+
 #[cfg_attr(test, allow(dead_code))]
 pub mod guard {
+    use crate::cell::Cell;
+    use crate::io;
     use crate::ops::Range;
+
     pub type Guard = Range<usize>;
+
+    pub const PAGE_SIZE: usize = 0x1000;
+
+    // See `set_own_stack`, zero for threads whose stack std didn't allocate.
+    #[thread_local]
+    static OWN_STACK: Cell<(usize, usize)> = Cell::new((0, 0));
+
+    const PERM_NONE: u64 = 0;
+    const PERM_RW: u64 = 3;
+
+    unsafe fn svc_set_memory_permission(addr: usize, size: usize, perm: u64) -> u32 {
+        let mut x0 = addr as u64;
+        let mut x1 = size as u64;
+        let mut x2 = perm;
+        llvm_asm!("svc 0x2"
+            : "+{x0}"(x0), "+{x1}"(x1), "+{x2}"(x2)
+            :
+            : "memory"
+            : "volatile");
+        x0 as u32
+    }
+
+    // Makes the page at `addr` inaccessible.
+    pub unsafe fn protect(addr: *mut u8) -> io::Result<()> {
+        match svc_set_memory_permission(addr as usize, PAGE_SIZE, PERM_NONE) {
+            0 => Ok(()),
+            rc => Err(io::Error::from_raw_os_error(rc as i32)),
+        }
+    }
+
+    pub unsafe fn unprotect(addr: *mut u8) {
+        let rc = svc_set_memory_permission(addr as usize, PAGE_SIZE, PERM_RW);
+        debug_assert_eq!(rc, 0);
+    }
+
+    // Records the stack std allocated for the current thread, as the address
+    // of its guard page and the size of the stack right above it. Called by
+    // `thread_start` before anything else runs on the thread.
+    pub unsafe fn set_own_stack(guard: usize, size: usize) {
+        OWN_STACK.set((guard, size));
+    }
+
+    // Called at the start of every thread spawned by std. nn::os may run a
+    // thread on an alias of the stack it was given, in which case the page we
+    // protected on the heap doesn't border the running stack at all. So a
+    // guard is only reported if the stack pointer actually lies within the
+    // allocation std protected.
     pub unsafe fn current() -> Option<Guard> {
-        None
+        let (guard, size) = OWN_STACK.get();
+        if guard == 0 {
+            return None;
+        }
+
+        let sp: usize;
+        llvm_asm!("mov $0, sp" : "=r"(sp) ::: "volatile");
+
+        let stackaddr = guard + PAGE_SIZE;
+        if sp < stackaddr || sp > stackaddr + size {
+            return None;
+        }
+        Some(guard..stackaddr)
     }
+
+    // There's no telling where the stack of the main thread (or any other
+    // thread we didn't create) ends, so no guard is reported for it.
     pub unsafe fn init() -> Option<Guard> {
         None
     }
 }
 
-fn min_stack_size(_: *const libc::pthread_attr_t) -> usize {
-    0x1000 // just a guess
-}
\ No newline at end of file
+fn min_stack_size() -> usize {
+    os::page_size()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{cleanup, DEFAULT_PRIORITY, DETACHED, DETACHED_LOCK, IDEAL_CORE_USE_DEFAULT};
+    use crate::mem;
+    use crate::ptr;
+    use crate::sync::atomic::Ordering;
+    use crate::sync::mpsc::channel;
+    use crate::sys::ext::thread::{JoinHandleExt, RawThread};
+    use crate::thread;
+    use crate::time::Duration;
+
+    use nnsdk::os::ThreadType;
+
+    // Whether the detached thread is finished, or `None` once it was reaped.
+    unsafe fn detached_state(raw: RawThread) -> Option<bool> {
+        let _lock = DETACHED_LOCK.lock();
+        DETACHED
+            .iter()
+            .find(|native| native.raw() == raw)
+            .map(|native| native.finished.load(Ordering::Acquire))
+    }
+
+    #[test]
+    fn detached_threads_are_reaped_at_cleanup() {
+        let handle = thread::spawn(|| {});
+        let raw = handle.as_raw_thread();
+        drop(handle);
+
+        unsafe {
+            while detached_state(raw) == Some(false) {
+                thread::sleep(Duration::from_millis(1));
+            }
+            cleanup();
+            assert_eq!(detached_state(raw), None);
+        }
+    }
+
+    #[test]
+    fn foreign_threads_are_adopted() {
+        const STACK_SIZE: usize = 0x10000;
+
+        #[repr(C, align(4096))]
+        struct Stack([u8; STACK_SIZE]);
+
+        static mut STACK: Stack = Stack([0; STACK_SIZE]);
+        // Name and ID `thread::current()` reported, and the kernel thread ID.
+        static mut SEEN: Option<(Option<String>, u64, u64)> = None;
+
+        extern "C" fn foreign_main(_: *mut libc::c_void) {
+            let current = thread::current();
+            unsafe {
+                let native = nnsdk::os::GetThreadId(nnsdk::os::GetCurrentThread());
+                let name = current.name().map(str::to_owned);
+                SEEN = Some((name, current.id().as_u64().get(), native));
+            }
+        }
+
+        unsafe {
+            let mut raw: ThreadType = mem::zeroed();
+            let rc = nnsdk::os::CreateThread(
+                &mut raw,
+                Some(foreign_main),
+                ptr::null_mut(),
+                STACK.0.as_mut_ptr() as *mut _,
+                STACK_SIZE as _,
+                DEFAULT_PRIORITY,
+                IDEAL_CORE_USE_DEFAULT,
+            );
+            assert_eq!(rc, 0);
+            nnsdk::os::SetThreadNamePointer(&mut raw, b"foreign\0".as_ptr() as _);
+            nnsdk::os::StartThread(&mut raw);
+            nnsdk::os::WaitThread(&mut raw);
+            nnsdk::os::DestroyThread(&mut raw);
+
+            let (name, id, native) = SEEN.take().expect("the thread didn't run");
+            assert_eq!(name.as_deref(), Some("foreign"));
+            assert_eq!(id, native | 1 << 63);
+        }
+    }
+
+    #[test]
+    fn join_timeout_hands_back_a_running_thread() {
+        let (tx, rx) = channel();
+        let handle = thread::spawn(move || {
+            rx.recv().unwrap();
+            7
+        });
+
+        let handle = match JoinHandleExt::join_timeout(handle, Duration::from_millis(10)) {
+            Ok(_) => panic!("joined a thread that is still blocked"),
+            Err(handle) => handle,
+        };
+        assert!(!handle.as_raw_thread().is_null());
+
+        tx.send(()).unwrap();
+        assert_eq!(handle.join().unwrap(), 7);
+    }
+}
@@ -2,30 +2,40 @@
 
 use crate::mem;
 
-pub type Key = libc::pthread_key_t;
+use nnsdk::os::TlsSlot;
+
+// nn::os TLS slots are a thin wrapper around an index, keep the index itself
+// as the key so that it fits into a `StaticKey`.
+pub type Key = u32;
+
+#[inline]
+fn slot(key: Key) -> TlsSlot {
+    unsafe { mem::transmute::<Key, TlsSlot>(key) }
+}
 
 #[inline]
 pub unsafe fn create(dtor: Option<unsafe extern "C" fn(*mut u8)>) -> Key {
-    let mut key = 0;
-    assert_eq!(libc::pthread_key_create(&mut key, mem::transmute(dtor)), 0);
-    key
+    let mut slot = mem::zeroed::<TlsSlot>();
+    // nn::os runs the destructor with the slot's value when a thread exits,
+    // the same way pthread keys do.
+    let r = nnsdk::os::AllocateTlsSlot(&mut slot, mem::transmute(dtor));
+    assert_eq!(r, 0, "failed to allocate a TLS slot");
+    mem::transmute::<TlsSlot, Key>(slot)
 }
 
 #[inline]
 pub unsafe fn set(key: Key, value: *mut u8) {
-    let r = libc::pthread_setspecific(key, value as *mut _);
-    debug_assert_eq!(r, 0);
+    nnsdk::os::SetTlsValue(slot(key), value as usize as _);
 }
 
 #[inline]
 pub unsafe fn get(key: Key) -> *mut u8 {
-    libc::pthread_getspecific(key) as *mut u8
+    nnsdk::os::GetTlsValue(slot(key)) as usize as *mut u8
 }
 
 #[inline]
 pub unsafe fn destroy(key: Key) {
-    let r = libc::pthread_key_delete(key);
-    debug_assert_eq!(r, 0);
+    nnsdk::os::FreeTlsSlot(slot(key));
 }
 
 #[inline]
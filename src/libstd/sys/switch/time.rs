@@ -9,17 +9,39 @@ pub struct SystemTime(Duration);
 
 pub const UNIX_EPOCH: SystemTime = SystemTime(Duration::from_secs(0));
 
-impl Instant {
-    pub fn now() -> Instant {
-        unsafe {
-            let mut ptime = PosixTime {
-                time: 0,
-            };
+const NSEC_PER_SEC: u64 = 1_000_000_000;
+
+// `Instant` is backed by the ARM generic timer, which Horizon lets userspace
+// read directly. This is the same counter `nn::os::GetSystemTick` returns, so
+// it is monotonic across all cores and unaffected by changes to the user's
+// clock.
+fn system_tick() -> u64 {
+    let ticks: u64;
+    unsafe {
+        llvm_asm!("mrs $0, cntpct_el0" : "=r"(ticks) ::: "volatile");
+    }
+    ticks
+}
+
+fn system_tick_frequency() -> u64 {
+    let freq: u64;
+    unsafe {
+        llvm_asm!("mrs $0, cntfrq_el0" : "=r"(freq) ::: "volatile");
+    }
+    freq
+}
 
-            nnsdk::time::StandardUserSystemClock::GetCurrentTime(&mut ptime);
+// Split into whole seconds and the remainder so that the multiplication can't
+// overflow: the remainder is below `freq`, which comfortably fits in 32 bits.
+fn ticks_to_duration(ticks: u64, freq: u64) -> Duration {
+    let secs = ticks / freq;
+    let nanos = (ticks % freq) * NSEC_PER_SEC / freq;
+    Duration::new(secs, nanos as u32)
+}
 
-            Instant(Duration::new(ptime.time, 0))
-        }
+impl Instant {
+    pub fn now() -> Instant {
+        Instant(ticks_to_duration(system_tick(), system_tick_frequency()))
     }
 
     pub const fn zero() -> Instant {
@@ -27,7 +49,7 @@ impl Instant {
     }
 
     pub fn actually_monotonic() -> bool {
-        false
+        true
     }
 
     pub fn checked_sub_instant(&self, other: &Instant) -> Option<Duration> {
@@ -43,17 +65,28 @@ impl Instant {
     }
 }
 
+fn posix_time_now() -> u64 {
+    unsafe {
+        let mut ptime = PosixTime {
+            time: 0,
+        };
+
+        nnsdk::time::StandardUserSystemClock::GetCurrentTime(&mut ptime);
+
+        ptime.time
+    }
+}
+
 impl SystemTime {
+    // The user system clock only has a resolution of one second, and there
+    // is no clock std can get at that carries the sub-second part, so the time
+    // is always a whole number of seconds.
     pub fn now() -> SystemTime {
-        unsafe {
-            let mut ptime = PosixTime {
-                time: 0,
-            };
-
-            nnsdk::time::StandardUserSystemClock::GetCurrentTime(&mut ptime);
+        SystemTime(Duration::from_secs(posix_time_now()))
+    }
 
-            SystemTime(Duration::new(ptime.time, 0))
-        }
+    pub fn from_posix_time(time: PosixTime) -> SystemTime {
+        SystemTime(Duration::from_secs(time.time))
     }
 
     pub fn sub_time(&self, other: &SystemTime) -> Result<Duration, Duration> {
@@ -68,3 +101,80 @@ impl SystemTime {
         Some(SystemTime(self.0.checked_sub(*other)?))
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{ticks_to_duration, NSEC_PER_SEC};
+    use crate::time::Duration;
+
+    // The switch's generic timer runs at 19.2MHz.
+    const FREQ: u64 = 19_200_000;
+
+    // Inverse of `ticks_to_duration`, rounding up so that every tick count
+    // survives a round trip even though a tick isn't a whole nanosecond.
+    fn duration_to_ticks(dur: Duration, freq: u64) -> u64 {
+        let sub = dur.subsec_nanos() as u64 * freq;
+        dur.as_secs() * freq + (sub + NSEC_PER_SEC - 1) / NSEC_PER_SEC
+    }
+
+    #[test]
+    fn whole_seconds() {
+        assert_eq!(ticks_to_duration(0, FREQ), Duration::from_secs(0));
+        assert_eq!(ticks_to_duration(FREQ, FREQ), Duration::from_secs(1));
+        assert_eq!(ticks_to_duration(FREQ * 3600, FREQ), Duration::from_secs(3600));
+    }
+
+    #[test]
+    fn sub_second() {
+        assert_eq!(ticks_to_duration(FREQ / 2, FREQ), Duration::from_millis(500));
+        assert_eq!(ticks_to_duration(FREQ / 1000, FREQ), Duration::from_millis(1));
+        // A single tick is 52.083ns, truncated.
+        assert_eq!(ticks_to_duration(1, FREQ), Duration::from_nanos(52));
+    }
+
+    #[test]
+    fn no_overflow() {
+        let d = ticks_to_duration(u64::max_value(), FREQ);
+        assert_eq!(d.as_secs(), u64::max_value() / FREQ);
+        assert!(d.subsec_nanos() < NSEC_PER_SEC as u32);
+    }
+
+    #[test]
+    fn round_trip() {
+        let samples = [0, 1, 2, 3, 7, 19, 1000, FREQ - 1, FREQ, FREQ + 1, 123_456_789_012, 1 << 40];
+        for &ticks in samples.iter() {
+            assert_eq!(duration_to_ticks(ticks_to_duration(ticks, FREQ), FREQ), ticks);
+        }
+        for ticks in 0..(2 * FREQ / 1000) {
+            assert_eq!(duration_to_ticks(ticks_to_duration(ticks, FREQ), FREQ), ticks);
+        }
+    }
+
+    #[test]
+    fn system_time_follows_the_clock() {
+        use super::{posix_time_now, SystemTime};
+
+        let start = posix_time_now();
+        let mut last = SystemTime::now();
+        // Long enough to see at least one second boundary.
+        while posix_time_now() < start + 2 {
+            let before = posix_time_now();
+            let now = SystemTime::now();
+            let after = posix_time_now();
+            assert!(now >= last, "{:?} went back to {:?}", last, now);
+            assert!(before <= now.0.as_secs() && now.0.as_secs() <= after);
+            assert_eq!(now.0.subsec_nanos(), 0);
+            last = now;
+        }
+    }
+
+    #[test]
+    fn monotonic() {
+        let mut last = crate::time::Instant::now();
+        for _ in 0..1000 {
+            let now = crate::time::Instant::now();
+            assert!(now >= last);
+            last = now;
+        }
+    }
+}
@@ -0,0 +1,65 @@
+//! Support for "weak linkage" to SDK functions on Horizon
+//!
+//! We are loaded into a title by nn::ro, which resolves our imports against
+//! whatever the title's SDK happens to export. Not every title links every
+//! part of the SDK, and an import that can't be resolved is left pointing at
+//! null, so calling it just crashes. Functions that not every title has are
+//! instead looked up by their mangled name at runtime, which lets us fall back
+//! to something else if they are missing.
+
+use crate::ffi::CStr;
+use crate::marker;
+use crate::mem;
+use crate::os::raw::c_char;
+use crate::sync::atomic::{AtomicUsize, Ordering};
+
+macro_rules! weak {
+    (#[link_name = $symbol:literal] fn $name:ident($($t:ty),*) -> $ret:ty) => (
+        #[allow(non_upper_case_globals)]
+        static $name: crate::sys::weak::Weak<unsafe extern "C" fn($($t),*) -> $ret> =
+            crate::sys::weak::Weak::new(concat!($symbol, '\0'));
+    )
+}
+
+extern "C" {
+    // nn::ro::LookupSymbol(unsigned long*, char const*)
+    #[link_name = "_ZN2nn2ro12LookupSymbolEPmPKc"]
+    fn LookupSymbol(addr: *mut usize, name: *const c_char) -> u32;
+}
+
+pub struct Weak<F> {
+    name: &'static str,
+    addr: AtomicUsize,
+    _marker: marker::PhantomData<F>,
+}
+
+impl<F> Weak<F> {
+    pub const fn new(name: &'static str) -> Weak<F> {
+        Weak { name, addr: AtomicUsize::new(1), _marker: marker::PhantomData }
+    }
+
+    pub fn get(&self) -> Option<F> {
+        assert_eq!(mem::size_of::<F>(), mem::size_of::<usize>());
+        unsafe {
+            if self.addr.load(Ordering::SeqCst) == 1 {
+                self.addr.store(fetch(self.name), Ordering::SeqCst);
+            }
+            match self.addr.load(Ordering::SeqCst) {
+                0 => None,
+                addr => Some(mem::transmute_copy::<usize, F>(&addr)),
+            }
+        }
+    }
+}
+
+unsafe fn fetch(name: &str) -> usize {
+    let name = match CStr::from_bytes_with_nul(name.as_bytes()) {
+        Ok(cstr) => cstr,
+        Err(..) => return 0,
+    };
+    let mut addr = 0;
+    match LookupSymbol(&mut addr, name.as_ptr()) {
+        0 => addr,
+        _ => 0,
+    }
+}
@@ -129,6 +129,8 @@ pub fn cleanup() {
         sys::args::cleanup();
         sys::stack_overflow::cleanup();
         at_exit_imp::cleanup();
+        #[cfg(target_os = "switch")]
+        sys::thread::cleanup();
     });
 }
 
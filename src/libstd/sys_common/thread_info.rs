@@ -20,7 +20,7 @@ impl ThreadInfo {
             .try_with(move |c| {
                 if c.borrow().is_none() {
                     *c.borrow_mut() =
-                        Some(ThreadInfo { stack_guard: None, thread: Thread::new(None) })
+                        Some(ThreadInfo { stack_guard: None, thread: foreign_thread() })
                 }
                 f(c.borrow_mut().as_mut().unwrap())
             })
@@ -28,6 +28,18 @@ impl ThreadInfo {
     }
 }
 
+// Most code on switch runs in hooks on threads the game created, adopt those
+// with the name and ID nn::os knows them by.
+#[cfg(target_os = "switch")]
+fn foreign_thread() -> Thread {
+    Thread::foreign()
+}
+
+#[cfg(not(target_os = "switch"))]
+fn foreign_thread() -> Thread {
+    Thread::new(None)
+}
+
 pub fn current_thread() -> Option<Thread> {
     ThreadInfo::with(|info| info.thread.clone())
 }
@@ -166,13 +166,21 @@ use crate::num::NonZeroU64;
 use crate::panic;
 use crate::panicking;
 use crate::str;
+#[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
 use crate::sync::atomic::AtomicUsize;
+#[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
 use crate::sync::atomic::Ordering::SeqCst;
-use crate::sync::{Arc, Condvar, Mutex};
+use crate::sync::Arc;
+#[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
+use crate::sync::{Condvar, Mutex};
 use crate::sys::thread as imp;
+#[cfg(all(target_os = "switch", target_feature = "atomics"))]
+use crate::sys::thread_parker::Parker;
 use crate::sys_common::mutex;
 use crate::sys_common::thread;
 use crate::sys_common::thread_info;
+#[cfg(target_os = "switch")]
+use crate::sys_common::AsInnerMut;
 use crate::sys_common::{AsInner, IntoInner};
 use crate::time::Duration;
 
@@ -260,6 +268,9 @@ pub struct Builder {
     name: Option<String>,
     // The size of the stack for the spawned thread in bytes
     stack_size: Option<usize>,
+    // Platform-specific spawn options, see `std::os::switch::thread::BuilderExt`
+    #[cfg(target_os = "switch")]
+    options: imp::SpawnOptions,
 }
 
 impl Builder {
@@ -283,7 +294,12 @@ impl Builder {
     /// ```
     #[stable(feature = "rust1", since = "1.0.0")]
     pub fn new() -> Builder {
-        Builder { name: None, stack_size: None }
+        Builder {
+            name: None,
+            stack_size: None,
+            #[cfg(target_os = "switch")]
+            options: imp::SpawnOptions::new(),
+        }
     }
 
     /// Names the thread-to-be. Currently the name is used for identification
@@ -455,7 +471,10 @@ impl Builder {
         F: Send + 'a,
         T: Send + 'a,
     {
+        #[cfg(not(target_os = "switch"))]
         let Builder { name, stack_size } = self;
+        #[cfg(target_os = "switch")]
+        let Builder { name, stack_size, options } = self;
 
         let stack_size = stack_size.unwrap_or_else(thread::min_stack);
 
@@ -477,24 +496,28 @@ impl Builder {
             *their_packet.get() = Some(try_result);
         };
 
+        // `imp::Thread::new` takes a closure with a `'static` lifetime, since it's passed
+        // through FFI or otherwise used with low-level threading primitives that have no
+        // notion of or way to enforce lifetimes.
+        //
+        // As mentioned in the `Safety` section of this function's documentation, the caller of
+        // this function needs to guarantee that the passed-in lifetime is sufficiently long
+        // for the lifetime of the thread.
+        //
+        // Similarly, the `sys` implementation must guarantee that no references to the closure
+        // exist after the thread has terminated, which is signaled by `Thread::join`
+        // returning.
+        let main = mem::transmute::<Box<dyn FnOnce() + 'a>, Box<dyn FnOnce() + 'static>>(
+            Box::new(main),
+        );
+
+        #[cfg(not(target_os = "switch"))]
+        let native = imp::Thread::new(stack_size, main)?;
+        #[cfg(target_os = "switch")]
+        let native = imp::Thread::new_with_options(stack_size, &options, main)?;
+
         Ok(JoinHandle(JoinInner {
-            // `imp::Thread::new` takes a closure with a `'static` lifetime, since it's passed
-            // through FFI or otherwise used with low-level threading primitives that have no
-            // notion of or way to enforce lifetimes.
-            //
-            // As mentioned in the `Safety` section of this function's documentation, the caller of
-            // this function needs to guarantee that the passed-in lifetime is sufficiently long
-            // for the lifetime of the thread.
-            //
-            // Similarly, the `sys` implementation must guarantee that no references to the closure
-            // exist after the thread has terminated, which is signaled by `Thread::join`
-            // returning.
-            native: Some(imp::Thread::new(
-                stack_size,
-                mem::transmute::<Box<dyn FnOnce() + 'a>, Box<dyn FnOnce() + 'static>>(Box::new(
-                    main,
-                )),
-            )?),
+            native: Some(native),
             thread: my_thread,
             packet: Packet(my_packet),
         }))
@@ -790,8 +813,11 @@ pub fn sleep(dur: Duration) {
 }
 
 // constants for park/unpark
+#[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
 const EMPTY: usize = 0;
+#[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
 const PARKED: usize = 1;
+#[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
 const NOTIFIED: usize = 2;
 
 /// Blocks unless or until the current thread's token is made available.
@@ -888,38 +914,49 @@ const NOTIFIED: usize = 2;
 // future, this will be implemented in a more efficient way, perhaps along the lines of
 //   http://cr.openjdk.java.net/~stefank/6989984.1/raw_files/new/src/os/linux/vm/os_linux.cpp
 // or futuxes, and in either case may allow spurious wakeups.
+//
+// On the Switch with atomics enabled, threads park on a futex instead, see
+// `sys::switch::thread_parker`. That one may wake up spuriously.
 #[stable(feature = "rust1", since = "1.0.0")]
 pub fn park() {
-    let thread = current();
-
-    // If we were previously notified then we consume this notification and
-    // return quickly.
-    if thread.inner.state.compare_exchange(NOTIFIED, EMPTY, SeqCst, SeqCst).is_ok() {
-        return;
+    #[cfg(all(target_os = "switch", target_feature = "atomics"))]
+    {
+        // The thread only ever parks on its own parker.
+        unsafe { current().inner.parker.park() }
     }
+    #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
+    {
+        let thread = current();
 
-    // Otherwise we need to coordinate going to sleep
-    let mut m = thread.inner.lock.lock().unwrap();
-    match thread.inner.state.compare_exchange(EMPTY, PARKED, SeqCst, SeqCst) {
-        Ok(_) => {}
-        Err(NOTIFIED) => {
-            // We must read here, even though we know it will be `NOTIFIED`.
-            // This is because `unpark` may have been called again since we read
-            // `NOTIFIED` in the `compare_exchange` above. We must perform an
-            // acquire operation that synchronizes with that `unpark` to observe
-            // any writes it made before the call to unpark. To do that we must
-            // read from the write it made to `state`.
-            let old = thread.inner.state.swap(EMPTY, SeqCst);
-            assert_eq!(old, NOTIFIED, "park state changed unexpectedly");
+        // If we were previously notified then we consume this notification and
+        // return quickly.
+        if thread.inner.state.compare_exchange(NOTIFIED, EMPTY, SeqCst, SeqCst).is_ok() {
             return;
-        } // should consume this notification, so prohibit spurious wakeups in next park.
-        Err(_) => panic!("inconsistent park state"),
-    }
-    loop {
-        m = thread.inner.cvar.wait(m).unwrap();
-        match thread.inner.state.compare_exchange(NOTIFIED, EMPTY, SeqCst, SeqCst) {
-            Ok(_) => return, // got a notification
-            Err(_) => {}     // spurious wakeup, go back to sleep
+        }
+
+        // Otherwise we need to coordinate going to sleep
+        let mut m = thread.inner.lock.lock().unwrap();
+        match thread.inner.state.compare_exchange(EMPTY, PARKED, SeqCst, SeqCst) {
+            Ok(_) => {}
+            Err(NOTIFIED) => {
+                // We must read here, even though we know it will be `NOTIFIED`.
+                // This is because `unpark` may have been called again since we read
+                // `NOTIFIED` in the `compare_exchange` above. We must perform an
+                // acquire operation that synchronizes with that `unpark` to observe
+                // any writes it made before the call to unpark. To do that we must
+                // read from the write it made to `state`.
+                let old = thread.inner.state.swap(EMPTY, SeqCst);
+                assert_eq!(old, NOTIFIED, "park state changed unexpectedly");
+                return;
+            } // should consume this notification, so prohibit spurious wakeups in next park.
+            Err(_) => panic!("inconsistent park state"),
+        }
+        loop {
+            m = thread.inner.cvar.wait(m).unwrap();
+            match thread.inner.state.compare_exchange(NOTIFIED, EMPTY, SeqCst, SeqCst) {
+                Ok(_) => return, // got a notification
+                Err(_) => {}     // spurious wakeup, go back to sleep
+            }
         }
     }
 }
@@ -987,35 +1024,43 @@ pub fn park_timeout_ms(ms: u32) {
 /// [park]: fn.park.html
 #[stable(feature = "park_timeout", since = "1.4.0")]
 pub fn park_timeout(dur: Duration) {
-    let thread = current();
-
-    // Like `park` above we have a fast path for an already-notified thread, and
-    // afterwards we start coordinating for a sleep.
-    // return quickly.
-    if thread.inner.state.compare_exchange(NOTIFIED, EMPTY, SeqCst, SeqCst).is_ok() {
-        return;
+    #[cfg(all(target_os = "switch", target_feature = "atomics"))]
+    {
+        // The thread only ever parks on its own parker.
+        unsafe { current().inner.parker.park_timeout(dur) }
     }
-    let m = thread.inner.lock.lock().unwrap();
-    match thread.inner.state.compare_exchange(EMPTY, PARKED, SeqCst, SeqCst) {
-        Ok(_) => {}
-        Err(NOTIFIED) => {
-            // We must read again here, see `park`.
-            let old = thread.inner.state.swap(EMPTY, SeqCst);
-            assert_eq!(old, NOTIFIED, "park state changed unexpectedly");
+    #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
+    {
+        let thread = current();
+
+        // Like `park` above we have a fast path for an already-notified thread, and
+        // afterwards we start coordinating for a sleep.
+        // return quickly.
+        if thread.inner.state.compare_exchange(NOTIFIED, EMPTY, SeqCst, SeqCst).is_ok() {
             return;
-        } // should consume this notification, so prohibit spurious wakeups in next park.
-        Err(_) => panic!("inconsistent park_timeout state"),
-    }
+        }
+        let m = thread.inner.lock.lock().unwrap();
+        match thread.inner.state.compare_exchange(EMPTY, PARKED, SeqCst, SeqCst) {
+            Ok(_) => {}
+            Err(NOTIFIED) => {
+                // We must read again here, see `park`.
+                let old = thread.inner.state.swap(EMPTY, SeqCst);
+                assert_eq!(old, NOTIFIED, "park state changed unexpectedly");
+                return;
+            } // should consume this notification, so prohibit spurious wakeups in next park.
+            Err(_) => panic!("inconsistent park_timeout state"),
+        }
 
-    // Wait with a timeout, and if we spuriously wake up or otherwise wake up
-    // from a notification we just want to unconditionally set the state back to
-    // empty, either consuming a notification or un-flagging ourselves as
-    // parked.
-    let (_m, _result) = thread.inner.cvar.wait_timeout(m, dur).unwrap();
-    match thread.inner.state.swap(EMPTY, SeqCst) {
-        NOTIFIED => {} // got a notification, hurray!
-        PARKED => {}   // no notification, alas
-        n => panic!("inconsistent park_timeout state: {}", n),
+        // Wait with a timeout, and if we spuriously wake up or otherwise wake up
+        // from a notification we just want to unconditionally set the state back to
+        // empty, either consuming a notification or un-flagging ourselves as
+        // parked.
+        let (_m, _result) = thread.inner.cvar.wait_timeout(m, dur).unwrap();
+        match thread.inner.state.swap(EMPTY, SeqCst) {
+            NOTIFIED => {} // got a notification, hurray!
+            PARKED => {}   // no notification, alas
+            n => panic!("inconsistent park_timeout state: {}", n),
+        }
     }
 }
 
@@ -1073,6 +1118,14 @@ impl ThreadId {
         }
     }
 
+    // IDs of adopted threads are derived from their kernel thread ID. Those
+    // are never reused either, and setting the top bit keeps them apart from
+    // the IDs handed out above.
+    #[cfg(target_os = "switch")]
+    fn from_native(id: u64) -> ThreadId {
+        ThreadId(NonZeroU64::new(id | 1 << 63).unwrap())
+    }
+
     /// This returns a numeric identifier for the thread identified by this
     /// `ThreadId`.
     ///
@@ -1097,8 +1150,13 @@ struct Inner {
     id: ThreadId,
 
     // state for thread park/unpark
+    #[cfg(all(target_os = "switch", target_feature = "atomics"))]
+    parker: Parker,
+    #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
     state: AtomicUsize,
+    #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
     lock: Mutex<()>,
+    #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
     cvar: Condvar,
 }
 
@@ -1141,8 +1199,34 @@ impl Thread {
             inner: Arc::new(Inner {
                 name: cname,
                 id: ThreadId::new(),
+                #[cfg(all(target_os = "switch", target_feature = "atomics"))]
+                parker: Parker::new(),
+                #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
                 state: AtomicUsize::new(EMPTY),
+                #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
                 lock: Mutex::new(()),
+                #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
+                cvar: Condvar::new(),
+            }),
+        }
+    }
+
+    // Used to adopt a thread std didn't spawn, with the name and ID it already
+    // has on the nn::os side.
+    #[cfg(target_os = "switch")]
+    pub(crate) fn foreign() -> Thread {
+        let (name, id) = imp::current_native();
+        Thread {
+            inner: Arc::new(Inner {
+                name,
+                id: ThreadId::from_native(id),
+                #[cfg(all(target_os = "switch", target_feature = "atomics"))]
+                parker: Parker::new(),
+                #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
+                state: AtomicUsize::new(EMPTY),
+                #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
+                lock: Mutex::new(()),
+                #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
                 cvar: Condvar::new(),
             }),
         }
@@ -1182,32 +1266,39 @@ impl Thread {
     /// [park]: fn.park.html
     #[stable(feature = "rust1", since = "1.0.0")]
     pub fn unpark(&self) {
-        // To ensure the unparked thread will observe any writes we made
-        // before this call, we must perform a release operation that `park`
-        // can synchronize with. To do that we must write `NOTIFIED` even if
-        // `state` is already `NOTIFIED`. That is why this must be a swap
-        // rather than a compare-and-swap that returns if it reads `NOTIFIED`
-        // on failure.
-        match self.inner.state.swap(NOTIFIED, SeqCst) {
-            EMPTY => return,    // no one was waiting
-            NOTIFIED => return, // already unparked
-            PARKED => {}        // gotta go wake someone up
-            _ => panic!("inconsistent state in unpark"),
+        #[cfg(all(target_os = "switch", target_feature = "atomics"))]
+        {
+            self.inner.parker.unpark();
         }
+        #[cfg(not(all(target_os = "switch", target_feature = "atomics")))]
+        {
+            // To ensure the unparked thread will observe any writes we made
+            // before this call, we must perform a release operation that `park`
+            // can synchronize with. To do that we must write `NOTIFIED` even if
+            // `state` is already `NOTIFIED`. That is why this must be a swap
+            // rather than a compare-and-swap that returns if it reads `NOTIFIED`
+            // on failure.
+            match self.inner.state.swap(NOTIFIED, SeqCst) {
+                EMPTY => return,    // no one was waiting
+                NOTIFIED => return, // already unparked
+                PARKED => {}        // gotta go wake someone up
+                _ => panic!("inconsistent state in unpark"),
+            }
 
-        // There is a period between when the parked thread sets `state` to
-        // `PARKED` (or last checked `state` in the case of a spurious wake
-        // up) and when it actually waits on `cvar`. If we were to notify
-        // during this period it would be ignored and then when the parked
-        // thread went to sleep it would never wake up. Fortunately, it has
-        // `lock` locked at this stage so we can acquire `lock` to wait until
-        // it is ready to receive the notification.
-        //
-        // Releasing `lock` before the call to `notify_one` means that when the
-        // parked thread wakes it doesn't get woken only to have to wait for us
-        // to release `lock`.
-        drop(self.inner.lock.lock().unwrap());
-        self.inner.cvar.notify_one()
+            // There is a period between when the parked thread sets `state` to
+            // `PARKED` (or last checked `state` in the case of a spurious wake
+            // up) and when it actually waits on `cvar`. If we were to notify
+            // during this period it would be ignored and then when the parked
+            // thread went to sleep it would never wake up. Fortunately, it has
+            // `lock` locked at this stage so we can acquire `lock` to wait until
+            // it is ready to receive the notification.
+            //
+            // Releasing `lock` before the call to `notify_one` means that when the
+            // parked thread wakes it doesn't get woken only to have to wait for us
+            // to release `lock`.
+            drop(self.inner.lock.lock().unwrap());
+            self.inner.cvar.notify_one()
+        }
     }
 
     /// Gets the thread's unique identifier.
@@ -1500,6 +1591,27 @@ impl<T> IntoInner<imp::Thread> for JoinHandle<T> {
     }
 }
 
+#[cfg(target_os = "switch")]
+impl<T> JoinHandle<T> {
+    // Backs `std::os::switch::thread::JoinHandleExt::join_timeout`.
+    pub(crate) fn join_timeout(mut self, dur: Duration) -> crate::result::Result<Result<T>, Self> {
+        match self.0.native.take().unwrap().join_timeout(dur) {
+            Ok(()) => Ok(unsafe { (*self.0.packet.0.get()).take().unwrap() }),
+            Err(native) => {
+                self.0.native = Some(native);
+                Err(self)
+            }
+        }
+    }
+}
+
+#[cfg(target_os = "switch")]
+impl AsInnerMut<imp::SpawnOptions> for Builder {
+    fn as_inner_mut(&mut self) -> &mut imp::SpawnOptions {
+        &mut self.options
+    }
+}
+
 #[stable(feature = "std_debug", since = "1.16.0")]
 impl<T> fmt::Debug for JoinHandle<T> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {